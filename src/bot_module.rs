@@ -0,0 +1,1249 @@
+use std::{
+    fmt,
+    any::{
+        Any,
+    },
+    panic::{
+        AssertUnwindSafe,
+    },
+    ops::{
+        Deref,
+    },
+    sync::{
+        Arc,
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+    },
+    time::{
+        Duration,
+    },
+    collections::{
+        BTreeMap,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use futures::{
+    future::{
+        BoxFuture,
+    },
+    FutureExt,
+};
+
+use serde::{
+    Serialize,
+    Deserialize,
+};
+
+use tokio::{
+    sync::{
+        mpsc,
+        Mutex,
+        Notify,
+    },
+    task::{
+        JoinHandle,
+    },
+    time::{
+        Instant,
+    },
+};
+
+use telegram_bot::{
+    types::{
+        ChatId,
+        Integer,
+    },
+    Update,
+    Message,
+    UpdateKind,
+    MessageKind,
+};
+
+use crate::{
+    metrics,
+    clock::{
+        Clock,
+    },
+    health::{
+        Health,
+    },
+    state_store::{
+        StateStore,
+        Versioned,
+    },
+    telegram_client::{
+        TelegramClient,
+    },
+};
+
+pub const DEFAULT_MAX_FAILURES_IN_ROW_STR: &str = "3";
+pub const DEFAULT_RESTART_BACKOFF_MIN_S_STR: &str = "1";
+pub const DEFAULT_RESTART_BACKOFF_MAX_S_STR: &str = "300";
+pub const DEFAULT_QUEUE_SIZE_STR: &str = "64";
+
+const CHAT_MIGRATIONS_STATE_KEY: &str = "registry/chat_migrations";
+
+/// Shutdown timeout without `--module-shutdown-timeout-s`: the time the
+/// modules need to finish their work, as estimated with `fit_shutdown_timeout`,
+/// plus this much.
+const SHUTDOWN_TIMEOUT_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// restart a module after this many update handling failures in a row
+    #[clap(long = "module-max-failures-in-row", default_value = DEFAULT_MAX_FAILURES_IN_ROW_STR)]
+    module_max_failures_in_row: usize,
+
+    /// initial delay before a failed module or background task is restarted (in seconds)
+    #[clap(long = "module-restart-backoff-min-s", default_value = DEFAULT_RESTART_BACKOFF_MIN_S_STR)]
+    module_restart_backoff_min_s: u64,
+
+    /// maximum delay before a failed module or background task is restarted (in seconds)
+    #[clap(long = "module-restart-backoff-max-s", default_value = DEFAULT_RESTART_BACKOFF_MAX_S_STR)]
+    module_restart_backoff_max_s: u64,
+
+    /// time given to a module to finish its work on stop (in seconds), enough for the final delete_recover check if omitted
+    #[clap(long = "module-shutdown-timeout-s")]
+    module_shutdown_timeout_s: Option<u64>,
+
+    /// updates buffered for a module while it is busy with the previous ones
+    #[clap(long = "module-queue-size", default_value = DEFAULT_QUEUE_SIZE_STR)]
+    module_queue_size: usize,
+}
+
+/// Shared resources handed to every module.
+#[derive(Clone)]
+pub struct Context {
+    pub client: Arc<dyn TelegramClient>,
+    pub state_store: Arc<StateStore>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Update handed to the modules: the `telegram_bot` update along with the
+/// fields it does not parse. Derefs to the update.
+#[derive(Debug)]
+pub struct IncomingUpdate {
+    pub update: Update,
+    /// forum topic of the message (`message_thread_id`), `None` outside forum topics
+    pub maybe_message_thread_id: Option<Integer>,
+}
+
+impl IncomingUpdate {
+    /// Picks the extra fields out of `raw`, the json the update was decoded from.
+    pub fn new(update: Update, raw: &serde_json::Value) -> IncomingUpdate {
+        // non-forum supergroups have reply threads as well, those are no topics
+        let maybe_message_thread_id = raw.get("message")
+            .or_else(|| raw.get("edited_message"))
+            .filter(|message| message["is_topic_message"].as_bool() == Some(true))
+            .and_then(|message| message["message_thread_id"].as_i64());
+        IncomingUpdate { update, maybe_message_thread_id, }
+    }
+}
+
+impl From<Update> for IncomingUpdate {
+    fn from(update: Update) -> IncomingUpdate {
+        IncomingUpdate { update, maybe_message_thread_id: None, }
+    }
+}
+
+impl Deref for IncomingUpdate {
+    type Target = Update;
+
+    fn deref(&self) -> &Update {
+        &self.update
+    }
+}
+
+/// Type erased module error, keeps the original module error for `Debug` output.
+pub struct Error(Box<dyn fmt::Debug + Send>);
+
+impl Error {
+    pub fn new<E>(error: E) -> Error where E: fmt::Debug + Send + 'static {
+        Error(Box::new(error))
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Module state handed over from a stopped instance to its reconfigured replacement.
+pub type State = Box<dyn Any + Send>;
+
+/// Long running job owned by a module, spawned by the registry after module
+/// init. A run which fails or panics is restarted with backoff, one which
+/// returns `Ok` is done for good.
+pub struct BackgroundTask {
+    pub name: String,
+    /// Makes a fresh run of the task: called on spawn and on every restart.
+    pub run: Box<dyn FnMut() -> BoxFuture<'static, Result<(), Error>> + Send>,
+}
+
+#[async_trait::async_trait]
+pub trait BotModule: Send {
+    /// Module name used in logs.
+    fn name(&self) -> &str;
+
+    async fn init(&mut self, _context: &Context) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn handle_update(&mut self, update: &IncomingUpdate, context: &Context) -> Result<(), Error>;
+
+    /// Called once right after a successful `init`.
+    fn background_tasks(&mut self, _context: &Context) -> Vec<BackgroundTask> {
+        Vec::new()
+    }
+
+    /// Called when a group is upgraded to a supergroup: the module should
+    /// replace `from` chat id with `to` and return `true` if it used the
+    /// chat. Such a module is restarted.
+    fn migrate_chat(&mut self, _from: ChatId, _to: ChatId) -> bool {
+        false
+    }
+
+    /// Called when the instance is stopped, the module should finish its
+    /// pending work here. Background tasks are aborted when it returns or
+    /// when the shutdown timeout expires.
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called after `shutdown` when the instance is replaced on config reload.
+    fn take_state(&mut self) -> Option<State> {
+        None
+    }
+
+    /// Called on a replacement instance before its `init`.
+    fn restore_state(&mut self, _state: State) {
+    }
+}
+
+/// Group to supergroup migrations seen so far, as saved in the state store.
+/// They are applied to every module instance before its start: the config
+/// keeps the old group id until it is updated by hand.
+#[derive(Default, Serialize, Deserialize)]
+struct ChatMigrations {
+    migrated: BTreeMap<i64, i64>,
+}
+
+impl Versioned for ChatMigrations {
+    const VERSION: u32 = 1;
+}
+
+impl ChatMigrations {
+    fn load(state_store: &StateStore) -> ChatMigrations {
+        match state_store.load(CHAT_MIGRATIONS_STATE_KEY) {
+            Ok(maybe_migrations) =>
+                maybe_migrations.unwrap_or_default(),
+            Err(error) => {
+                log::error!("failed to load chat migrations: {:?}", error);
+                ChatMigrations::default()
+            },
+        }
+    }
+
+    fn apply(&self, module: &mut dyn BotModule) {
+        for (&from, &to) in &self.migrated {
+            if module.migrate_chat(ChatId::new(from), ChatId::new(to)) {
+                log::info!("module {:?}: chat {} is migrated to supergroup {}", module.name(), from, to);
+            }
+        }
+    }
+}
+
+/// Supervised module slot: failure counters, restart schedule and the worker
+/// task feeding updates to the module.
+struct Entry {
+    name: String,
+    module: Arc<Mutex<Box<dyn BotModule>>>,
+    config: Box<dyn Any + Send>,
+    background_tasks: Vec<(String, JoinHandle<()>)>,
+    /// Tasks of the module waiting for restart, reported as restarting.
+    restarting_tasks: Vec<String>,
+    maybe_worker: Option<Worker>,
+    failures_total: Arc<AtomicUsize>,
+    restart_backoff: Duration,
+    maybe_restart_at: Option<Instant>,
+    shutdown_timeout: Duration,
+    max_failures_in_row: usize,
+    queue_size: usize,
+    restart_backoff_min: Duration,
+    restart_backoff_max: Duration,
+    health: Arc<Health>,
+    worker_exited: Arc<Notify>,
+}
+
+/// Task handing buffered updates to the module one by one, in the order they
+/// were dispatched: updates of a chat are never reordered.
+struct Worker {
+    update_tx: mpsc::Sender<Arc<IncomingUpdate>>,
+    /// Set when the worker returns or panics, possibly before `handle` is finished.
+    exited: Arc<AtomicBool>,
+    handle: JoinHandle<WorkerExit>,
+}
+
+/// Marks the worker exited and wakes the registry up when dropped, panic included.
+struct WorkerExitSignal {
+    exited: Arc<AtomicBool>,
+    worker_exited: Arc<Notify>,
+}
+
+impl Drop for WorkerExitSignal {
+    fn drop(&mut self) {
+        self.exited.store(true, Ordering::SeqCst);
+        self.worker_exited.notify_one();
+    }
+}
+
+enum WorkerExit {
+    /// Update channel is closed and all the buffered updates are handled.
+    Drained,
+    /// `max_failures_in_row` updates failed in a row, the rest are dropped.
+    Failed {
+        succeeded: bool,
+    },
+}
+
+pub struct Registry {
+    max_failures_in_row: usize,
+    restart_backoff_min: Duration,
+    restart_backoff_max: Duration,
+    maybe_fixed_shutdown_timeout: Option<Duration>,
+    shutdown_timeout: Duration,
+    queue_size: usize,
+    health: Arc<Health>,
+    worker_exited: Arc<Notify>,
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    /// Background tasks are reported to `health`.
+    pub fn new(cli_args: &CliArgs, health: Arc<Health>) -> Registry {
+        let restart_backoff_min = Duration::from_secs(cli_args.module_restart_backoff_min_s);
+        Registry {
+            max_failures_in_row: cli_args.module_max_failures_in_row.max(1),
+            restart_backoff_min,
+            restart_backoff_max: Duration::from_secs(cli_args.module_restart_backoff_max_s)
+                .max(restart_backoff_min),
+            maybe_fixed_shutdown_timeout: cli_args.module_shutdown_timeout_s.map(Duration::from_secs),
+            shutdown_timeout: cli_args.module_shutdown_timeout_s.map_or(SHUTDOWN_TIMEOUT_MARGIN, Duration::from_secs),
+            queue_size: cli_args.module_queue_size.max(1),
+            health,
+            worker_exited: Arc::new(Notify::new()),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Sets the shutdown timeout from `needed`, the time the modules take to
+    /// finish their work on stop, unless it is fixed on the command line.
+    pub fn fit_shutdown_timeout(&mut self, needed: Duration) {
+        if self.maybe_fixed_shutdown_timeout.is_some() {
+            return;
+        }
+        self.shutdown_timeout = needed + SHUTDOWN_TIMEOUT_MARGIN;
+        for entry in self.entries.iter_mut() {
+            entry.shutdown_timeout = self.shutdown_timeout;
+        }
+    }
+
+    /// Completes when a module may be due for a restart: a worker has given
+    /// up on its module or a scheduled restart time has come. The future does
+    /// not borrow the registry, so it can be selected along with the updates.
+    pub fn restart_due(&self) -> BoxFuture<'static, ()> {
+        let worker_exited = self.worker_exited.clone();
+        let maybe_restart_at = self.entries.iter()
+            .filter_map(|entry| entry.maybe_restart_at)
+            .min();
+        async move {
+            match maybe_restart_at {
+                Some(restart_at) =>
+                    tokio::select! {
+                        () = worker_exited.notified() => (),
+                        () = tokio::time::sleep_until(restart_at) => (),
+                    },
+                None =>
+                    worker_exited.notified().await,
+            }
+        }.boxed()
+    }
+
+    /// Schedules restarts of the modules whose workers have given up and
+    /// restarts the ones whose backoff has expired. Called on `restart_due`,
+    /// so a failed module comes back with its background tasks even when no
+    /// updates arrive.
+    pub async fn restart_failed(&mut self, context: &Context) {
+        for entry in self.entries.iter_mut() {
+            entry.reap_failed_worker(self.restart_backoff_min, self.restart_backoff_max).await;
+            entry.restart_if_due(context, self.restart_backoff_max).await;
+        }
+    }
+
+    /// Brings running instances of the module kind (identified by config type
+    /// `C`) in line with `configs`, `make` builds a module from its config.
+    /// Instances are matched by module name, which must stay the same for the
+    /// same instance whatever its position in `configs`. Instances with
+    /// unchanged config are left alone, changed ones are restarted with their
+    /// state carried over, removed ones are stopped and new ones are started.
+    /// A module which fails to start is retried with backoff. Chat migrations
+    /// seen before are applied to the new instances.
+    pub async fn reload<C, M, F>(&mut self, configs: &[C], make: F, context: &Context)
+    where C: PartialEq + Clone + Send + 'static,
+          M: BotModule + 'static,
+          F: Fn(&C) -> M,
+    {
+        let chat_migrations = ChatMigrations::load(&context.state_store);
+        let mut names = Vec::with_capacity(configs.len());
+        for config in configs {
+            let mut module = make(config);
+            chat_migrations.apply(&mut module);
+            names.push(module.name().to_string());
+
+            let maybe_position = self.entries.iter()
+                .position(|entry| entry.name == module.name() && entry.config.is::<C>());
+            match maybe_position {
+                Some(position) if self.entries[position].config.downcast_ref::<C>() == Some(config) =>
+                    log::debug!("module {:?} config is not changed", module.name()),
+                Some(position) => {
+                    log::info!("module {:?} config is changed, restarting", module.name());
+                    let mut entry = self.make_entry(module, config);
+                    let old_entry = &mut self.entries[position];
+                    old_entry.stop().await;
+                    if let Some(state) = old_entry.module.lock().await.take_state() {
+                        entry.module.lock().await.restore_state(state);
+                    }
+                    entry.start_or_schedule_restart(context, self.restart_backoff_max).await;
+                    self.entries[position] = entry;
+                },
+                None => {
+                    log::info!("module {:?} is added, starting", module.name());
+                    let mut entry = self.make_entry(module, config);
+                    entry.start_or_schedule_restart(context, self.restart_backoff_max).await;
+                    self.entries.push(entry);
+                },
+            }
+        }
+
+        let mut index = 0;
+        while index < self.entries.len() {
+            let entry = &mut self.entries[index];
+            if entry.config.is::<C>() && !names.iter().any(|name| name == &entry.name) {
+                log::info!("module {:?} is removed, stopping", entry.name);
+                entry.stop().await;
+                self.entries.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// Queues the update for every module which is currently up, modules
+    /// handle their queues concurrently. Waits only when a module queue is
+    /// full. A failing module never affects the others: it is restarted with
+    /// backoff after `max_failures_in_row` consecutive failures.
+    pub async fn dispatch(&mut self, update: IncomingUpdate, context: &Context) {
+        if let Some((from, to)) = chat_migration(&update) {
+            self.migrate_chat(from, to, context).await;
+        }
+
+        let update = Arc::new(update);
+        for entry in self.entries.iter_mut() {
+            entry.reap_failed_worker(self.restart_backoff_min, self.restart_backoff_max).await;
+            if !entry.restart_if_due(context, self.restart_backoff_max).await {
+                log::debug!("module {:?} is waiting for restart, skipping update {}", entry.name, update.id);
+                continue;
+            }
+            entry.enqueue(update.clone()).await;
+        }
+    }
+
+    async fn migrate_chat(&mut self, from: ChatId, to: ChatId, context: &Context) {
+        let mut chat_migrations = ChatMigrations::load(&context.state_store);
+        if chat_migrations.migrated.insert(from.into(), to.into()).is_none() {
+            if let Err(error) = context.state_store.save(CHAT_MIGRATIONS_STATE_KEY, &chat_migrations) {
+                log::error!("failed to save chat migration {} to {}: {:?}", from, to, error);
+            }
+        }
+
+        for entry in self.entries.iter_mut() {
+            if !entry.module.lock().await.migrate_chat(from, to) {
+                continue;
+            }
+            log::warn!(
+                "module {:?}: chat {} migrated to supergroup {}, please update the config",
+                entry.name,
+                from,
+                to,
+            );
+            if entry.maybe_restart_at.is_some() {
+                // is going to be restarted anyway
+                continue;
+            }
+            entry.stop().await;
+            entry.start_or_schedule_restart(context, self.restart_backoff_max).await;
+        }
+    }
+
+    /// Stops all the modules concurrently, each one within the shutdown
+    /// timeout. Updates already queued for a module are handled first.
+    pub async fn shutdown(&mut self) {
+        let stops = self.entries.iter_mut()
+            // the ones waiting for restart are stopped already
+            .filter(|entry| entry.maybe_restart_at.is_none())
+            .map(Entry::stop);
+        futures::future::join_all(stops).await;
+    }
+
+    fn make_entry<C, M>(&self, module: M, config: &C) -> Entry where C: Clone + Send + 'static, M: BotModule + 'static {
+        Entry {
+            name: module.name().to_string(),
+            module: Arc::new(Mutex::new(Box::new(module))),
+            config: Box::new(config.clone()),
+            background_tasks: Vec::new(),
+            restarting_tasks: Vec::new(),
+            maybe_worker: None,
+            failures_total: Arc::new(AtomicUsize::new(0)),
+            restart_backoff: self.restart_backoff_min,
+            maybe_restart_at: None,
+            shutdown_timeout: self.shutdown_timeout,
+            max_failures_in_row: self.max_failures_in_row,
+            queue_size: self.queue_size,
+            restart_backoff_min: self.restart_backoff_min,
+            restart_backoff_max: self.restart_backoff_max,
+            health: self.health.clone(),
+            worker_exited: self.worker_exited.clone(),
+        }
+    }
+}
+
+impl Entry {
+    async fn start(&mut self, context: &Context) -> Result<(), Error> {
+        let mut module = self.module.lock().await;
+        module.init(context).await?;
+        for task in module.background_tasks(context) {
+            let name = format!("{}/{}", self.name, task.name);
+            log::info!("background task {:?} has spawned", name);
+            let supervisor = supervise_task(
+                name.clone(),
+                task.run,
+                self.health.clone(),
+                self.restart_backoff_min,
+                self.restart_backoff_max,
+            );
+            self.background_tasks.push((name, tokio::spawn(supervisor)));
+        }
+        drop(module);
+        for name in self.restarting_tasks.drain(..) {
+            if !self.background_tasks.iter().any(|(task_name, _)| *task_name == name) {
+                self.health.task_stopped(&name);
+            }
+        }
+
+        let (update_tx, update_rx) = mpsc::channel(self.queue_size);
+        let worker = run_worker(
+            self.name.clone(),
+            self.module.clone(),
+            update_rx,
+            context.clone(),
+            self.max_failures_in_row,
+            self.failures_total.clone(),
+        );
+        let exited = Arc::new(AtomicBool::new(false));
+        let exit_signal = WorkerExitSignal { exited: exited.clone(), worker_exited: self.worker_exited.clone(), };
+        let handle = tokio::spawn(async move {
+            let _exit_signal = exit_signal;
+            worker.await
+        });
+        self.maybe_worker = Some(Worker { update_tx, exited, handle, });
+        Ok(())
+    }
+
+    /// Restarts the module if its restart time has come, returns `true` if
+    /// the module is up.
+    async fn restart_if_due(&mut self, context: &Context, restart_backoff_max: Duration) -> bool {
+        match self.maybe_restart_at {
+            None =>
+                true,
+            Some(restart_at) if Instant::now() < restart_at =>
+                false,
+            Some(..) => {
+                self.maybe_restart_at = None;
+                log::info!("restarting module {:?}", self.name);
+                self.start_or_schedule_restart(context, restart_backoff_max).await
+            },
+        }
+    }
+
+    /// Returns `true` if the module is up.
+    async fn start_or_schedule_restart(&mut self, context: &Context, restart_backoff_max: Duration) -> bool {
+        match self.start(context).await {
+            Ok(()) =>
+                true,
+            Err(error) => {
+                log::error!("module {:?} start failed: {:?}", self.name, error);
+                self.schedule_restart(restart_backoff_max).await;
+                false
+            },
+        }
+    }
+
+    async fn enqueue(&mut self, update: Arc<IncomingUpdate>) {
+        let worker = match self.maybe_worker.as_ref() {
+            Some(worker) =>
+                worker,
+            None =>
+                return,
+        };
+        let update_id = update.id;
+        let send_result = match worker.update_tx.try_send(update) {
+            Ok(()) =>
+                Ok(()),
+            Err(mpsc::error::TrySendError::Full(update)) => {
+                log::warn!("module {:?} queue is full ({} updates), waiting to queue update {}", self.name, self.queue_size, update_id);
+                worker.update_tx.send(update).await
+                    .map_err(|_send_error| ())
+            },
+            Err(mpsc::error::TrySendError::Closed(..)) =>
+                Err(()),
+        };
+        match send_result {
+            Ok(()) => {
+                let queue_depth = self.queue_size - worker.update_tx.capacity();
+                metrics::MODULE_QUEUE_DEPTH.with_label_values(&[&self.name]).set(queue_depth as i64);
+                log::debug!("module {:?} queue depth: {}", self.name, queue_depth);
+            },
+            Err(()) =>
+                log::debug!("module {:?} worker is gone, dropping update {}", self.name, update_id),
+        }
+    }
+
+    /// Schedules a restart if the worker gave up on the module or panicked.
+    async fn reap_failed_worker(&mut self, restart_backoff_min: Duration, restart_backoff_max: Duration) {
+        if !self.maybe_worker.as_ref().is_some_and(|worker| worker.exited.load(Ordering::SeqCst)) {
+            return;
+        }
+        let worker = self.maybe_worker.take().unwrap();
+        match worker.handle.await {
+            Ok(WorkerExit::Failed { succeeded: true, }) =>
+                self.restart_backoff = restart_backoff_min,
+            Ok(WorkerExit::Failed { succeeded: false, }) | Ok(WorkerExit::Drained) =>
+                (),
+            Err(join_error) =>
+                log::error!("module {:?} worker terminated: {:?}", self.name, join_error),
+        }
+        self.schedule_restart(restart_backoff_max).await;
+    }
+
+    /// Lets the worker handle the queued updates, then shuts the module down.
+    async fn stop(&mut self) {
+        let maybe_worker = self.maybe_worker.take();
+        let mut maybe_worker_handle = None;
+        let stopping = async {
+            if let Some(Worker { update_tx, handle, .. }) = maybe_worker {
+                drop(update_tx);
+                let handle = maybe_worker_handle.insert(handle);
+                if let Err(join_error) = handle.await {
+                    log::error!("module {:?} worker terminated: {:?}", self.name, join_error);
+                }
+            }
+            self.module.lock().await.shutdown().await
+        };
+        match tokio::time::timeout(self.shutdown_timeout, stopping).await {
+            Ok(Ok(())) =>
+                log::debug!("module {:?} is stopped", self.name),
+            Ok(Err(error)) =>
+                log::error!("module {:?} shutdown failed: {:?}", self.name, error),
+            Err(_elapsed) =>
+                log::warn!("module {:?} did not finish in {:?}, aborting it", self.name, self.shutdown_timeout),
+        }
+        if let Some(worker_handle) = maybe_worker_handle {
+            worker_handle.abort();
+        }
+        for (name, task) in self.background_tasks.drain(..) {
+            log::debug!("stopping background task {:?}", name);
+            task.abort();
+            self.health.task_stopped(&name);
+        }
+        for name in self.restarting_tasks.drain(..) {
+            self.health.task_stopped(&name);
+        }
+    }
+
+    /// Background tasks stay reported as restarting until the module is up
+    /// again: it is not ready meanwhile.
+    async fn schedule_restart(&mut self, restart_backoff_max: Duration) {
+        let mut task_names: Vec<_> = self.background_tasks.iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        // the ones of a restart which has failed already
+        task_names.append(&mut self.restarting_tasks);
+        self.stop().await;
+        for name in &task_names {
+            self.health.task_restarting(name);
+        }
+        self.restarting_tasks = task_names;
+        log::warn!("module {:?} is going to be restarted in {:?}", self.name, self.restart_backoff);
+        self.maybe_restart_at = Some(Instant::now() + self.restart_backoff);
+        self.restart_backoff = (self.restart_backoff * 2).min(restart_backoff_max);
+    }
+}
+
+async fn run_worker(
+    name: String,
+    module: Arc<Mutex<Box<dyn BotModule>>>,
+    mut update_rx: mpsc::Receiver<Arc<IncomingUpdate>>,
+    context: Context,
+    max_failures_in_row: usize,
+    failures_total: Arc<AtomicUsize>,
+)
+    -> WorkerExit
+{
+    let mut failures_in_row = 0;
+    let mut succeeded = false;
+    let queue_depth = metrics::MODULE_QUEUE_DEPTH.with_label_values(&[&name]);
+    let handle_duration = metrics::MODULE_HANDLE_DURATION.with_label_values(&[&name]);
+    let errors = metrics::MODULE_ERRORS.with_label_values(&[&name]);
+    while let Some(update) = update_rx.recv().await {
+        queue_depth.set(update_rx.len() as i64);
+        let mut module = module.lock().await;
+        let timer = handle_duration.start_timer();
+        let result = module.handle_update(&update, &context).await;
+        timer.observe_duration();
+        drop(module);
+        match result {
+            Ok(()) => {
+                failures_in_row = 0;
+                succeeded = true;
+            },
+            Err(error) => {
+                failures_in_row += 1;
+                errors.inc();
+                log::error!(
+                    "module {:?} failed on update {} ({} in a row, {} total): {:?}",
+                    name,
+                    update.id,
+                    failures_in_row,
+                    failures_total.fetch_add(1, Ordering::Relaxed) + 1,
+                    error,
+                );
+                if failures_in_row >= max_failures_in_row {
+                    return WorkerExit::Failed { succeeded, };
+                }
+            },
+        }
+    }
+    WorkerExit::Drained
+}
+
+/// Runs the background task until it is done, restarting failed and panicked
+/// runs with backoff. The backoff is reset after a run which lasted longer
+/// than `restart_backoff_max`.
+async fn supervise_task(
+    name: String,
+    mut run: Box<dyn FnMut() -> BoxFuture<'static, Result<(), Error>> + Send>,
+    health: Arc<Health>,
+    restart_backoff_min: Duration,
+    restart_backoff_max: Duration,
+)
+{
+    let mut restart_backoff = restart_backoff_min;
+    loop {
+        health.task_started(&name);
+        let started_at = Instant::now();
+        match AssertUnwindSafe(run()).catch_unwind().await {
+            Ok(Ok(())) => {
+                log::info!("background task {:?} is done", name);
+                health.task_finished(&name);
+                return;
+            },
+            Ok(Err(error)) =>
+                log::error!("background task {:?} failed: {:?}", name, error),
+            Err(_panic) =>
+                log::error!("background task {:?} panicked", name),
+        }
+        if started_at.elapsed() > restart_backoff_max {
+            restart_backoff = restart_backoff_min;
+        }
+        health.task_restarting(&name);
+        metrics::BACKGROUND_TASK_RESTARTS.with_label_values(&[&name]).inc();
+        log::warn!("background task {:?} is going to be restarted in {:?}", name, restart_backoff);
+        tokio::time::sleep(restart_backoff).await;
+        restart_backoff = (restart_backoff * 2).min(restart_backoff_max);
+    }
+}
+
+/// Group to supergroup migration: both the old group (`migrate_to_chat_id`)
+/// and the new supergroup (`migrate_from_chat_id`) receive a service message.
+fn chat_migration(update: &Update) -> Option<(ChatId, ChatId)> {
+    match &update.kind {
+        UpdateKind::Message(message @ Message { kind: MessageKind::MigrateToChatId { data }, .. }) =>
+            Some((message.chat.id(), ChatId::new(*data))),
+        UpdateKind::Message(message @ Message { kind: MessageKind::MigrateFromChatId { data }, .. }) =>
+            Some((ChatId::new(*data), message.chat.id())),
+        _ =>
+            None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::{
+            Duration,
+        },
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use futures::{
+        FutureExt,
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            GroupId,
+            SupergroupId,
+            MessageId,
+        },
+        Update,
+        UpdateKind,
+        Message,
+        MessageKind,
+        MessageChat,
+        User,
+        Group,
+        Supergroup,
+    };
+
+    use super::{
+        State,
+        Error,
+        Health,
+        BackgroundTask,
+        Context,
+        CliArgs,
+        Registry,
+        IncomingUpdate,
+        BotModule,
+        StateStore,
+    };
+
+    use crate::{
+        clock,
+        health,
+        telegram_client::{
+            fake::{
+                FakeClient,
+            },
+        },
+    };
+
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    #[derive(Clone, Default, PartialEq)]
+    struct Script {
+        id: usize,
+        fail: bool,
+        hang: bool,
+        slow: bool,
+        task_failures: usize,
+        version: usize,
+    }
+
+    impl Script {
+        fn new(id: usize) -> Script {
+            Script { id, ..Script::default() }
+        }
+    }
+
+    struct Scripted {
+        name: String,
+        script: Script,
+        chat_id: ChatId,
+        events: Events,
+    }
+
+    impl Scripted {
+        fn event(&self, event: &str) {
+            self.events.lock().unwrap().push(format!("{} {}", self.name, event));
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BotModule for Scripted {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        async fn init(&mut self, _context: &Context) -> Result<(), Error> {
+            self.event("init");
+            Ok(())
+        }
+
+        async fn handle_update(&mut self, update: &IncomingUpdate, _context: &Context) -> Result<(), Error> {
+            self.event("update");
+            if let UpdateKind::Message(message) = &update.kind {
+                if message.chat.id() == self.chat_id {
+                    self.event("chat message");
+                }
+            }
+            if self.script.slow {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            if self.script.fail {
+                Err(Error::new("scripted failure"))
+            } else {
+                Ok(())
+            }
+        }
+
+        async fn shutdown(&mut self) -> Result<(), Error> {
+            self.event("shutdown");
+            if self.script.hang {
+                futures::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+
+        /// The task fails `task_failures` times, panicking every other time.
+        fn background_tasks(&mut self, _context: &Context) -> Vec<BackgroundTask> {
+            let events = self.events.clone();
+            let name = self.name.clone();
+            let task_failures = self.script.task_failures;
+            let mut runs = 0;
+            vec![
+                BackgroundTask {
+                    name: "task".to_string(),
+                    run: Box::new(move || {
+                        runs += 1;
+                        events.lock().unwrap().push(format!("{} task run", name));
+                        let run = runs;
+                        async move {
+                            if run > task_failures {
+                                futures::future::pending::<()>().await;
+                            }
+                            if run % 2 == 0 {
+                                panic!("scripted panic");
+                            }
+                            Err(Error::new("scripted failure"))
+                        }.boxed()
+                    }),
+                },
+            ]
+        }
+
+        fn migrate_chat(&mut self, from: ChatId, to: ChatId) -> bool {
+            if self.chat_id == from {
+                self.chat_id = to;
+                true
+            } else {
+                false
+            }
+        }
+
+        fn take_state(&mut self) -> Option<State> {
+            Some(Box::new(self.script.version))
+        }
+
+        fn restore_state(&mut self, state: State) {
+            let version = state.downcast::<usize>().unwrap();
+            self.event(&format!("restored {}", version));
+        }
+    }
+
+    fn setup() -> (Registry, Context, Events) {
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "--module-max-failures-in-row", "2",
+            "--module-restart-backoff-min-s", "0",
+            "--module-shutdown-timeout-s", "600",
+        ]);
+        let context = Context {
+            client: Arc::new(FakeClient::new()),
+            state_store: Arc::new(StateStore::in_memory()),
+            clock: Arc::new(clock::SystemClock),
+        };
+        let health = Arc::new(Health::new(&health::CliArgs::parse_from(["test"]), true));
+        (Registry::new(&cli_args, health), context, Events::default())
+    }
+
+    fn make(events: &Events) -> impl Fn(&Script) -> Scripted + '_ {
+        move |script| Scripted {
+            name: format!("scripted#{}", script.id),
+            script: script.clone(),
+            chat_id: ChatId::new(GROUP_ID),
+            events: events.clone(),
+        }
+    }
+
+    const GROUP_ID: i64 = -1;
+    const SUPERGROUP_ID: i64 = -1001;
+
+    fn message(id: i64, chat: MessageChat, kind: MessageKind) -> IncomingUpdate {
+        let from = User {
+            id: 1.into(),
+            first_name: "Darya".to_string(),
+            last_name: None,
+            username: None,
+            is_bot: false,
+            language_code: None,
+        };
+        let message = Message {
+            id: MessageId::new(id),
+            from,
+            date: 0,
+            chat,
+            forward: None,
+            reply_to_message: None,
+            edit_date: None,
+            media_group_id: None,
+            kind,
+        };
+        Update { id, kind: UpdateKind::Message(message), }.into()
+    }
+
+    fn group() -> MessageChat {
+        MessageChat::Group(Group {
+            id: GroupId::new(GROUP_ID),
+            title: "beercan".to_string(),
+            all_members_are_administrators: false,
+            invite_link: None,
+        })
+    }
+
+    fn supergroup() -> MessageChat {
+        MessageChat::Supergroup(Supergroup {
+            id: SupergroupId::new(SUPERGROUP_ID),
+            title: "beercan".to_string(),
+            username: None,
+            invite_link: None,
+        })
+    }
+
+    fn text(text: &str) -> MessageKind {
+        MessageKind::Text { data: text.to_string(), entities: Vec::new(), }
+    }
+
+    fn count(events: &Events, event: &str) -> usize {
+        events.lock().unwrap().iter().filter(|logged| *logged == event).count()
+    }
+
+    /// Dispatches updates one at a time, letting the module workers handle each one.
+    async fn dispatch(registry: &mut Registry, context: &Context, count: i64) {
+        for id in 0 .. count {
+            registry.dispatch(Update { id, kind: UpdateKind::Unknown, }.into(), context).await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_module_does_not_stop_others() {
+        let (mut registry, context, events) = setup();
+        registry.reload(&[Script { fail: true, ..Script::new(0) }, Script::new(1)], make(&events), &context).await;
+
+        dispatch(&mut registry, &context, 5).await;
+
+        assert_eq!(count(&events, "scripted#1 update"), 5);
+        assert_eq!(count(&events, "scripted#1 init"), 1);
+        assert_eq!(count(&events, "scripted#0 update"), 5);
+        // restarted after the 2nd and the 4th failures
+        assert_eq!(count(&events, "scripted#0 init"), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reload_restarts_changed_only() {
+        let (mut registry, context, events) = setup();
+        registry.reload(&[Script::new(0), Script::new(1), Script::new(2)], make(&events), &context).await;
+
+        let changed = Script { version: 1, ..Script::new(1) };
+        registry.reload(&[Script::new(0), changed, Script::new(2), Script::new(3)], make(&events), &context).await;
+        dispatch(&mut registry, &context, 1).await;
+
+        assert_eq!(count(&events, "scripted#0 init"), 1);
+        assert_eq!(count(&events, "scripted#0 shutdown"), 0);
+        assert_eq!(count(&events, "scripted#1 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#1 restored 0"), 1);
+        assert_eq!(count(&events, "scripted#1 init"), 2);
+        assert_eq!(count(&events, "scripted#3 init"), 1);
+        assert_eq!(count(&events, "scripted#3 update"), 1);
+
+        registry.reload(&[Script::new(0)], make(&events), &context).await;
+        dispatch(&mut registry, &context, 1).await;
+
+        assert_eq!(count(&events, "scripted#0 update"), 2);
+        assert_eq!(count(&events, "scripted#1 update"), 1);
+        assert_eq!(count(&events, "scripted#2 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#3 shutdown"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reload_matches_instances_by_name() {
+        let (mut registry, context, events) = setup();
+        let first = Script { version: 10, ..Script::new(0) };
+        let second = Script { version: 11, ..Script::new(1) };
+        registry.reload(&[first, second], make(&events), &context).await;
+
+        // the second one moves to the first position and changes
+        let changed = Script { version: 12, ..Script::new(1) };
+        registry.reload(&[changed], make(&events), &context).await;
+
+        assert_eq!(count(&events, "scripted#0 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#1 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#1 restored 11"), 1);
+        assert_eq!(count(&events, "scripted#1 restored 10"), 0);
+        assert_eq!(count(&events, "scripted#1 init"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_does_not_wait_for_hanging_module() {
+        let (mut registry, context, events) = setup();
+        let hanging = Script { hang: true, ..Script::new(1) };
+        registry.reload(&[Script::new(0), hanging, Script::new(2)], make(&events), &context).await;
+
+        registry.shutdown().await;
+
+        assert_eq!(count(&events, "scripted#0 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#1 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#2 shutdown"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_module_does_not_hold_up_others() {
+        let (mut registry, context, events) = setup();
+        let slow = Script { slow: true, ..Script::new(0) };
+        let script = Script::new(1);
+        registry.reload(&[slow, script], make(&events), &context).await;
+
+        for id in 0 .. 3 {
+            registry.dispatch(Update { id, kind: UpdateKind::Unknown, }.into(), &context).await;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(count(&events, "scripted#0 update"), 1);
+        assert_eq!(count(&events, "scripted#1 update"), 3);
+
+        // queued updates are handled before the module is shut down
+        registry.shutdown().await;
+        assert_eq!(count(&events, "scripted#0 update"), 3);
+        assert_eq!(count(&events, "scripted#0 shutdown"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_background_task_is_restarted() {
+        let (mut registry, context, events) = setup();
+        let script = Script { task_failures: 3, ..Script::new(0) };
+        registry.reload(&[script], make(&events), &context).await;
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(count(&events, "scripted#0 task run"), 4);
+        assert_eq!(count(&events, "scripted#0 init"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn module_follows_migrated_chat() {
+        let (mut registry, context, events) = setup();
+        let script = Script::new(0);
+        registry.reload(std::slice::from_ref(&script), make(&events), &context).await;
+
+        // both the old group and the new supergroup get a service message
+        registry.dispatch(message(1, group(), MessageKind::MigrateToChatId { data: SUPERGROUP_ID, }), &context).await;
+        registry.dispatch(message(2, supergroup(), MessageKind::MigrateFromChatId { data: GROUP_ID, }), &context).await;
+        registry.dispatch(message(3, supergroup(), text("hello")), &context).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(count(&events, "scripted#0 init"), 2);
+        assert_eq!(count(&events, "scripted#0 shutdown"), 1);
+        assert_eq!(count(&events, "scripted#0 chat message"), 2);
+
+        // the migration is applied again after a restart with the old config
+        registry.shutdown().await;
+        let (mut registry, _context, _events) = setup();
+        registry.reload(&[script], make(&events), &context).await;
+        registry.dispatch(message(4, supergroup(), text("hello again")), &context).await;
+        registry.dispatch(message(5, group(), text("anyone here?")), &context).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        assert_eq!(count(&events, "scripted#0 chat message"), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn module_waiting_for_restart_is_not_ready() {
+        let (_registry, context, events) = setup();
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "--module-max-failures-in-row", "2",
+            "--module-restart-backoff-min-s", "10",
+        ]);
+        let health = Arc::new(Health::new(&health::CliArgs::parse_from(["test"]), false));
+        let mut registry = Registry::new(&cli_args, health.clone());
+        let failing = Script { fail: true, ..Script::new(0) };
+        registry.reload(&[failing], make(&events), &context).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(health.report().ready);
+
+        // the worker gives up after the 2nd failure, the 3rd dispatch schedules the restart
+        dispatch(&mut registry, &context, 3).await;
+        let report = health.report();
+        assert!(!report.ready);
+        assert_eq!(report.background_tasks.get("scripted#0/task"), Some(&health::TaskStatus::Restarting));
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        dispatch(&mut registry, &context, 1).await;
+        assert_eq!(count(&events, "scripted#0 init"), 2);
+        assert!(health.report().ready);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_module_is_restarted_without_updates() {
+        let (_registry, context, events) = setup();
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "--module-max-failures-in-row", "2",
+            "--module-restart-backoff-min-s", "10",
+        ]);
+        let mut registry = Registry::new(&cli_args, Arc::new(Health::new(&health::CliArgs::parse_from(["test"]), true)));
+        let failing = Script { fail: true, ..Script::new(0) };
+        registry.reload(&[failing], make(&events), &context).await;
+
+        // the worker gives up after the 2nd failure, no update follows
+        dispatch(&mut registry, &context, 2).await;
+        let restarting = async {
+            loop {
+                registry.restart_due().await;
+                registry.restart_failed(&context).await;
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(11), restarting).await;
+
+        assert_eq!(count(&events, "scripted#0 init"), 2);
+        assert_eq!(count(&events, "scripted#0 task run"), 2);
+    }
+
+    #[test]
+    fn message_thread_id_is_read_for_topic_messages_only() {
+        let incoming = |raw| IncomingUpdate::new(Update { id: 1, kind: UpdateKind::Unknown, }, &raw);
+        let topic_message = serde_json::json!({ "message": { "message_thread_id": 5, "is_topic_message": true } });
+        let thread_reply = serde_json::json!({ "edited_message": { "message_thread_id": 5 } });
+        assert_eq!(incoming(topic_message).maybe_message_thread_id, Some(5));
+        assert_eq!(incoming(thread_reply).maybe_message_thread_id, None);
+    }
+}
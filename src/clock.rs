@@ -0,0 +1,109 @@
+use std::{
+    time::{
+        Duration,
+    },
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use futures::{
+    future::{
+        BoxFuture,
+    },
+    FutureExt,
+};
+
+use tokio::{
+    time::{
+        Instant,
+    },
+};
+
+/// Wall time and timers for the modules, so schedules can run on virtual time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Completes after `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// System wall time with tokio timers.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// Wall time derived from the tokio clock: starts at `started_at` and moves
+/// only as tokio time does. With paused tokio time (`replay`, tests) a day
+/// passes as soon as everything is idle or `tokio::time::advance` is called.
+#[derive(Clone, Copy)]
+pub struct VirtualClock {
+    started_at: DateTime<Utc>,
+    origin: Instant,
+}
+
+impl VirtualClock {
+    pub fn new(started_at: DateTime<Utc>) -> VirtualClock {
+        VirtualClock {
+            started_at,
+            origin: Instant::now(),
+        }
+    }
+
+    /// Tokio instant at which this clock shows `datetime`, past ones are now.
+    pub fn instant_at(&self, datetime: DateTime<Utc>) -> Instant {
+        self.origin + (datetime - self.started_at).to_std().unwrap_or_default()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.started_at + chrono::Duration::from_std(self.origin.elapsed()).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        time::{
+            Duration,
+        },
+    };
+
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+
+    use super::{
+        Clock,
+        VirtualClock,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn virtual_clock_follows_tokio_time() {
+        let clock = VirtualClock::new(Utc.with_ymd_and_hms(2022, 3, 1, 10, 0, 0).unwrap());
+        tokio::time::sleep_until(clock.instant_at(Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap())).await;
+        assert_eq!(clock.now(), Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).unwrap());
+
+        clock.sleep(Duration::from_secs(30)).await;
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(clock.now(), Utc.with_ymd_and_hms(2022, 3, 1, 12, 1, 0).unwrap());
+        // the past is due immediately
+        assert_eq!(clock.instant_at(Utc.with_ymd_and_hms(2022, 3, 1, 9, 0, 0).unwrap()), clock.instant_at(Utc.with_ymd_and_hms(2022, 3, 1, 10, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    io,
+    fs,
+    fmt,
+    path::{
+        PathBuf,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use serde::{
+    Deserialize,
+};
+
+use crate::{
+    bot_module,
+    vaccine_reminder,
+    delete_recover,
+    scheduler,
+};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// toml config file describing bot modules
+    #[clap(short = 'c', long = "config")]
+    config_path: Option<PathBuf>,
+
+    /// telegram bot token (overrides config)
+    #[clap(short = 't', long = "telegram-bot-token")]
+    telegram_bot_token: Option<String>,
+
+    #[clap(flatten)]
+    vaccine_reminder: vaccine_reminder::CliArgs,
+
+    #[clap(flatten)]
+    delete_recover: delete_recover::CliArgs,
+}
+
+/// Config file layout. Every module is configured as a list of instances
+/// (`[[module]]` tables), a module without instances is not started.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    telegram_bot_token: Option<String>,
+    #[serde(default)]
+    vaccine_reminder: Vec<vaccine_reminder::Config>,
+    #[serde(default)]
+    delete_recover: Vec<delete_recover::Config>,
+    #[serde(default)]
+    scheduler: Vec<scheduler::Config>,
+}
+
+/// Effective configuration: config file with command line overrides applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub telegram_bot_token: String,
+    pub vaccine_reminder: Vec<vaccine_reminder::Config>,
+    pub delete_recover: Vec<delete_recover::Config>,
+    pub scheduler: Vec<scheduler::Config>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile { path: PathBuf, error: io::Error, },
+    ParseFile { path: PathBuf, error: toml::de::Error, },
+    MissingTelegramBotToken,
+    NoModules,
+    Module { module: &'static str, error: bot_module::Error, },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadFile { path, error, } =>
+                write!(f, "cannot read config file {:?}: {}", path, error),
+            Error::ParseFile { path, error, } =>
+                write!(f, "invalid config file {:?}: {}", path, error),
+            Error::MissingTelegramBotToken =>
+                write!(f, "telegram bot token is not set, use --telegram-bot-token or telegram_bot_token in the config file"),
+            Error::NoModules =>
+                write!(f, "no module instances configured, pass a config file with --config or module options on the command line"),
+            Error::Module { module, error, } =>
+                write!(f, "invalid {} config: {:?}", module, error),
+        }
+    }
+}
+
+pub fn load(cli_args: &CliArgs) -> Result<Config, Error> {
+    resolve(read(cli_args)?, cli_args)
+}
+
+/// Config for offline runs (`replay`): the bot token is not required.
+pub fn load_offline(cli_args: &CliArgs) -> Result<Config, Error> {
+    let mut config_file = read(cli_args)?;
+    config_file.telegram_bot_token.get_or_insert_with(String::new);
+    resolve(config_file, cli_args)
+}
+
+fn read(cli_args: &CliArgs) -> Result<ConfigFile, Error> {
+    let config_file = match &cli_args.config_path {
+        Some(path) => {
+            let contents = fs::read_to_string(path)
+                .map_err(|error| Error::ReadFile { path: path.clone(), error, })?;
+            parse(&contents)
+                .map_err(|error| Error::ParseFile { path: path.clone(), error, })?
+        },
+        None =>
+            ConfigFile::default(),
+    };
+    Ok(config_file)
+}
+
+fn parse(contents: &str) -> Result<ConfigFile, toml::de::Error> {
+    toml::from_str(contents)
+}
+
+fn resolve(config_file: ConfigFile, cli_args: &CliArgs) -> Result<Config, Error> {
+    let config = Config {
+        telegram_bot_token: cli_args.telegram_bot_token.clone()
+            .or(config_file.telegram_bot_token)
+            .ok_or(Error::MissingTelegramBotToken)?,
+        vaccine_reminder: cli_args.vaccine_reminder
+            .override_config(config_file.vaccine_reminder)
+            .map_err(|error| Error::Module { module: "vaccine_reminder", error: error.into(), })?,
+        delete_recover: cli_args.delete_recover
+            .override_config(config_file.delete_recover)
+            .map_err(|error| Error::Module { module: "delete_recover", error: error.into(), })?,
+        scheduler: {
+            scheduler::validate(&config_file.scheduler)
+                .map_err(|error| Error::Module { module: "scheduler", error: error.into(), })?;
+            config_file.scheduler
+        },
+    };
+    if config.vaccine_reminder.is_empty() && config.delete_recover.is_empty() && scheduler::enabled(&config.scheduler).is_empty() {
+        return Err(Error::NoModules);
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{
+        Parser,
+    };
+
+    use super::{
+        parse,
+        resolve,
+        Error,
+        CliArgs,
+    };
+
+    const BEERCAN_TOML: &str = include_str!("../beercan.toml");
+
+    #[test]
+    fn parse_beercan() {
+        let config_file = parse(BEERCAN_TOML).unwrap();
+        let config = resolve(config_file, &CliArgs::parse_from(["test", "-t", "token"])).unwrap();
+        assert_eq!(config.telegram_bot_token, "token");
+        assert_eq!(config.vaccine_reminder.len(), 1);
+        assert_eq!(config.vaccine_reminder[0].user_id, 337229462);
+        assert_eq!(config.delete_recover.len(), 1);
+        assert_eq!(config.delete_recover[0].forward_group_id, -756453207);
+        assert_eq!(config.delete_recover[0].window_size, 32);
+        assert_eq!(config.scheduler.len(), 1);
+        assert_eq!(config.scheduler[0].name, "good_morning_darya");
+        assert_eq!(config.scheduler[0].schedule.to_string(), "every day at 17:00");
+        assert_eq!(config.scheduler[0].time_zone, chrono_tz::Europe::Moscow);
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let config_file = parse(BEERCAN_TOML).unwrap();
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "-t", "token",
+            "--delete-recover-window-size", "8",
+        ]);
+        let config = resolve(config_file, &cli_args).unwrap();
+        assert_eq!(config.delete_recover[0].window_size, 8);
+    }
+
+    #[test]
+    fn cli_only_module() {
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "-t", "token",
+            "--vaccine-reminder-user-id", "1",
+            "--vaccine-reminder-group-id", "-2",
+        ]);
+        let config = resolve(Default::default(), &cli_args).unwrap();
+        assert_eq!(config.vaccine_reminder.len(), 1);
+        assert_eq!(config.vaccine_reminder[0].group_id, -2);
+        assert!(config.delete_recover.is_empty());
+        assert!(config.scheduler.is_empty());
+    }
+
+    #[test]
+    fn multiple_instances() {
+        let config_file = parse(r#"
+            [[vaccine_reminder]]
+            user_id = 1
+            group_id = -10
+
+            [[vaccine_reminder]]
+            user_id = 2
+            group_id = -20
+
+            [[delete_recover]]
+            user_id = 1
+            group_id = -10
+            forward_group_id = -11
+
+            [[delete_recover]]
+            user_id = 2
+            group_id = -20
+            forward_group_id = -21
+            window_size = 4
+        "#).unwrap();
+        let cli_args = CliArgs::parse_from(["test", "-t", "token", "--vaccine-reminder-user-id", "3"]);
+        let config = resolve(config_file, &cli_args).unwrap();
+        assert_eq!(config.vaccine_reminder.len(), 2);
+        assert_eq!(config.vaccine_reminder[0].user_id, 3);
+        assert_eq!(config.vaccine_reminder[1].user_id, 2);
+        assert_eq!(config.delete_recover.len(), 2);
+        assert_eq!(config.delete_recover[0].window_size, 32);
+        assert_eq!(config.delete_recover[1].window_size, 4);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(parse("[[vaccine_reminder]]\nuser_id = 1\ngroup_id = 2\ntypo = 3\n").is_err());
+        assert!(parse("[[delete_recover]]\nuser_id = 1\ngroup_id = 2\n").is_err());
+        assert!(parse("[[scheduler]]\nname = \"a\"\nschedule = \"every day at 25:00\"\ngroup_id = 2\nmessage = \"m\"\n").is_err());
+        assert!(parse("[[scheduler]]\nname = \"a\"\nschedule = \"0 9 * *\"\ngroup_id = 2\nmessage = \"m\"\n").is_err());
+        assert!(parse("[[scheduler]]\nname = \"a\"\nschedule = \"0 9 * * *\"\ntime_zone = \"Mars/Olympus\"\ngroup_id = 2\nmessage = \"m\"\n").is_err());
+        let job = "[[scheduler]]\nname = \"a\"\nschedule = \"0 9 * * *\"\ntime_zone = \"UTC\"\ngroup_id = 2\nmessage = \"m\"\n";
+        let config_file = parse(&format!("{}{}", job, job)).unwrap();
+        assert!(resolve(config_file, &CliArgs::parse_from(["test", "-t", "token"])).is_err());
+        let config_file = parse(&format!("{}grace_s = 9223372036854775807\n", job)).unwrap();
+        assert!(resolve(config_file, &CliArgs::parse_from(["test", "-t", "token"])).is_err());
+        assert!(resolve(Default::default(), &CliArgs::parse_from(["test"])).is_err());
+        let cli_args = CliArgs::parse_from(["test", "-t", "token", "--delete-recover-window-size", "0"]);
+        let config_file = parse("[[delete_recover]]\nuser_id = 1\ngroup_id = 2\nforward_group_id = 3\n").unwrap();
+        assert!(resolve(config_file, &cli_args).is_err());
+    }
+
+    #[test]
+    fn no_modules() {
+        let error = resolve(Default::default(), &CliArgs::parse_from(["test", "-t", "token"])).unwrap_err();
+        assert!(matches!(error, Error::NoModules));
+        let config_file = parse("[[scheduler]]\nname = \"a\"\nschedule = \"0 9 * * *\"\ntime_zone = \"UTC\"\ngroup_id = 2\nmessage = \"m\"\nenabled = false\n").unwrap();
+        assert!(matches!(resolve(config_file, &CliArgs::parse_from(["test", "-t", "token"])), Err(Error::NoModules)));
+    }
+}
@@ -1,9 +1,14 @@
 use std::{
+    sync::{
+        Arc,
+    },
     time::{
         Duration,
     },
     collections::{
         VecDeque,
+        HashMap,
+        HashSet,
     },
 };
 
@@ -18,8 +23,8 @@ use futures::{
 };
 
 use tokio::{
-    time::{
-        sleep,
+    sync::{
+        Mutex,
     },
 };
 
@@ -28,109 +33,277 @@ use clap::{
     AppSettings,
 };
 
+use serde::{
+    Serialize,
+    Deserialize,
+};
+
 use telegram_bot::{
     types::{
         UserId,
-        GroupId,
+        ChatId,
         Integer,
+        MessageId,
     },
-    Api,
     User,
-    Group,
-    Update,
     Message,
     ParseMode,
     UpdateKind,
     MessageChat,
-    SendMessage,
     MessageKind,
-    CanDeleteMessage,
-    CanForwardMessage,
+    ToMessageId,
+};
+
+use crate::{
+    metrics,
+    clock::{
+        Clock,
+    },
+    bot_module,
+    telegram_client::{
+        self,
+        TelegramClient,
+        OutgoingMessage,
+    },
+    state_store::{
+        self,
+        Versioned,
+        StateStore,
+    },
 };
 
-pub const DEFAULT_USER_ID_STR: &'static str = "337229462"; // Parviz Sadesi
-pub const DEFAULT_GROUP_ID_STR: &'static str = "-222927743"; // Beercan
-pub const DEFAULT_FORWARD_GROUP_ID_STR: &'static str = "-756453207"; // beercan delete monitor
-pub const DEFAULT_WINDOW_SIZE_STR: &'static str = "32";
-pub const DEFAULT_CHECK_TIMEOUT_S_STR: &'static str = "60";
+pub const DEFAULT_WINDOW_SIZE: usize = 32;
+pub const DEFAULT_CHECK_TIMEOUT_S: u64 = 60;
 
 #[derive(Clone, Debug, Parser)]
 #[clap(setting = AppSettings::DeriveDisplayOrder)]
 pub struct CliArgs {
-    /// user id to remind about vaccination
-    #[clap(long = "delete-recover-user-id", default_value = DEFAULT_USER_ID_STR, allow_hyphen_values = true)]
-    delete_recover_user_id: Integer,
+    /// user id to monitor for deleted messages (overrides config)
+    #[clap(long = "delete-recover-user-id", allow_hyphen_values = true)]
+    delete_recover_user_id: Option<Integer>,
 
-    /// group id to use
-    #[clap(long = "delete-recover-group-id", default_value = DEFAULT_GROUP_ID_STR, allow_hyphen_values = true)]
-    delete_recover_group_id: Integer,
+    /// group id to use (overrides config)
+    #[clap(long = "delete-recover-group-id", allow_hyphen_values = true)]
+    delete_recover_group_id: Option<Integer>,
 
-    /// group id to forward messages to (delete monitor)
-    #[clap(long = "delete-recover-forward-group-id", default_value = DEFAULT_FORWARD_GROUP_ID_STR, allow_hyphen_values = true)]
-    delete_recover_forward_group_id: Integer,
+    /// group id to forward messages to, delete monitor (overrides config)
+    #[clap(long = "delete-recover-forward-group-id", allow_hyphen_values = true)]
+    delete_recover_forward_group_id: Option<Integer>,
 
-    /// messages window size to monitor
-    #[clap(long = "delete-recover-window-size", default_value = DEFAULT_WINDOW_SIZE_STR)]
-    delete_recover_window_size: usize,
+    /// messages window size to monitor (overrides config)
+    #[clap(long = "delete-recover-window-size")]
+    delete_recover_window_size: Option<usize>,
+
+    /// check timeout before trying to forward messages, in seconds (overrides config)
+    #[clap(long = "delete-recover-check-timeout-s")]
+    delete_recover_check_timeout_s: Option<u64>,
+}
 
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// user id to monitor for deleted messages
+    pub user_id: Integer,
+    /// group or supergroup id to use
+    pub group_id: Integer,
+    /// group or supergroup id to forward messages to (delete monitor)
+    pub forward_group_id: Integer,
+    /// messages window size to monitor
+    #[serde(default = "default_window_size")]
+    pub window_size: usize,
     /// check timeout before trying to forward messages (in seconds)
-    #[clap(long = "delete-recover-check-timeout-s", default_value = DEFAULT_CHECK_TIMEOUT_S_STR)]
-    delete_recover_check_timeout_s: u64,
+    #[serde(default = "default_check_timeout_s")]
+    pub check_timeout_s: u64,
+}
+
+fn default_window_size() -> usize {
+    DEFAULT_WINDOW_SIZE
+}
+
+fn default_check_timeout_s() -> u64 {
+    DEFAULT_CHECK_TIMEOUT_S
+}
+
+impl CliArgs {
+    /// Applies command line overrides on top of the first `[[delete_recover]]`
+    /// config instance. An instance is created from the command line alone if
+    /// the config has none.
+    pub fn override_config(&self, mut configs: Vec<Config>) -> Result<Vec<Config>, Error> {
+        if configs.is_empty() {
+            if self.delete_recover_user_id.is_none()
+                && self.delete_recover_group_id.is_none()
+                && self.delete_recover_forward_group_id.is_none()
+            {
+                return Ok(configs);
+            }
+            configs.push(Config {
+                user_id: self.delete_recover_user_id.ok_or(Error::MissingUserId)?,
+                group_id: self.delete_recover_group_id.ok_or(Error::MissingGroupId)?,
+                forward_group_id: self.delete_recover_forward_group_id.ok_or(Error::MissingForwardGroupId)?,
+                window_size: DEFAULT_WINDOW_SIZE,
+                check_timeout_s: DEFAULT_CHECK_TIMEOUT_S,
+            });
+        }
+        let config = &mut configs[0];
+        if let Some(user_id) = self.delete_recover_user_id {
+            config.user_id = user_id;
+        }
+        if let Some(group_id) = self.delete_recover_group_id {
+            config.group_id = group_id;
+        }
+        if let Some(forward_group_id) = self.delete_recover_forward_group_id {
+            config.forward_group_id = forward_group_id;
+        }
+        if let Some(window_size) = self.delete_recover_window_size {
+            config.window_size = window_size;
+        }
+        if let Some(check_timeout_s) = self.delete_recover_check_timeout_s {
+            config.check_timeout_s = check_timeout_s;
+        }
+        let mut instances = HashSet::new();
+        for config in configs.iter() {
+            config.validate()?;
+            // the group and user ids identify the instance and its saved window
+            if !instances.insert((config.group_id, config.user_id)) {
+                return Err(Error::DuplicateInstance { user_id: config.user_id, group_id: config.group_id, });
+            }
+        }
+        Ok(configs)
+    }
+}
+
+/// Time the final window check on stop takes with full windows: forwards to
+/// the same monitor chat are sent `chat_interval` apart.
+pub fn final_check_duration(configs: &[Config], chat_interval: Duration) -> Duration {
+    let mut forwards: HashMap<Integer, usize> = HashMap::new();
+    for config in configs {
+        *forwards.entry(config.forward_group_id).or_default() += config.window_size;
+    }
+    let max_forwards = forwards.into_values().max().unwrap_or(0);
+    chat_interval.saturating_mul(max_forwards.min(u32::MAX as usize) as u32)
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), Error> {
+        if self.window_size == 0 {
+            return Err(Error::ZeroWindowSize);
+        }
+        if self.check_timeout_s == 0 {
+            return Err(Error::ZeroCheckTimeout);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    MissingUserId,
+    MissingGroupId,
+    MissingForwardGroupId,
+    ZeroWindowSize,
+    ZeroCheckTimeout,
+    DuplicateInstance { user_id: Integer, group_id: Integer, },
     MonitorTaskIsGone,
+    StateStore(state_store::Error),
+}
+
+/// Monitored message, keeps just enough to probe it and to report it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct WatchedMessage {
+    chat_id: Integer,
+    message_id: Integer,
+    reply_to_message_id: Option<Integer>,
+    #[serde(default)]
+    maybe_message_thread_id: Option<Integer>,
+    author: String,
+    maybe_text: Option<String>,
+}
+
+impl WatchedMessage {
+    fn new(message: &Message, maybe_message_thread_id: Option<Integer>) -> WatchedMessage {
+        let author = if let Some(username) = &message.from.username {
+            format!("@{}", username)
+        } else {
+            message.from.first_name.to_string()
+        };
+        let maybe_text = match &message.kind {
+            MessageKind::Text { data, .. } |
+            MessageKind::Document { caption: Some(data), .. } |
+            MessageKind::Photo { caption: Some(data), .. } |
+            MessageKind::Video { caption: Some(data), .. } =>
+                Some(data.clone()),
+            _ =>
+                None,
+        };
+        WatchedMessage {
+            chat_id: message.chat.id().into(),
+            message_id: message.id.into(),
+            reply_to_message_id: message.reply_to_message.as_ref()
+                .map(|reply_to_message| reply_to_message.to_message_id().into()),
+            maybe_message_thread_id,
+            author,
+            maybe_text,
+        }
+    }
 }
 
+/// Window as saved in the state store.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedWindow {
+    messages: VecDeque<WatchedMessage>,
+}
+
+impl Versioned for SavedWindow {
+    const VERSION: u32 = 1;
+}
+
+/// Messages being monitored. Owned by the monitor task while it runs, so
+/// acquiring the lock also means waiting for the monitor to finish.
+type Window = Arc<Mutex<VecDeque<WatchedMessage>>>;
+
 pub struct DeleteRecover {
+    name: String,
+    state_key: String,
+    legacy_state_key: String,
     user_id: UserId,
-    group_id: GroupId,
-    forward_group_id: GroupId,
+    chat_id: ChatId,
+    forward_chat_id: ChatId,
     window_size: usize,
     check_timeout_s: u64,
-    maybe_monitor_tx: Option<mpsc::Sender<Message>>,
+    window: Window,
+    maybe_monitor_tx: Option<mpsc::Sender<WatchedMessage>>,
 }
 
 impl DeleteRecover {
-    pub fn new(cli_args: &CliArgs) -> Result<DeleteRecover, Error> {
-        Ok(DeleteRecover {
-            user_id: cli_args.delete_recover_user_id.into(),
-            group_id: cli_args.delete_recover_group_id.into(),
-            forward_group_id: cli_args.delete_recover_forward_group_id.into(),
-            window_size: cli_args.delete_recover_window_size,
-            check_timeout_s: cli_args.delete_recover_check_timeout_s,
+    /// Instances are identified by group and user id rather than position: a
+    /// reload never hands the window of one instance over to another.
+    pub fn new(config: &Config) -> DeleteRecover {
+        DeleteRecover {
+            name: format!("delete_recover#{}/{}", config.group_id, config.user_id),
+            state_key: format!("delete_recover/{}/{}/window", config.group_id, config.user_id),
+            legacy_state_key: format!("delete_recover/{}/window", config.group_id),
+            user_id: config.user_id.into(),
+            chat_id: config.group_id.into(),
+            forward_chat_id: config.forward_group_id.into(),
+            window_size: config.window_size,
+            check_timeout_s: config.check_timeout_s,
+            window: Arc::new(Mutex::new(VecDeque::with_capacity(config.window_size))),
             maybe_monitor_tx: None,
-        })
+        }
     }
 
-    pub async fn process(&mut self, update: &Update, api: &Api) -> Result<(), Error> {
+    pub async fn process(&mut self, update: &bot_module::IncomingUpdate) -> Result<(), Error> {
         match &update.kind {
             UpdateKind::Message(message) =>
                 match message {
                     Message {
                         from: User { id: user_id, .. },
-                        chat: MessageChat::Group(Group { id: chat_id, .. }),
+                        chat: chat @ (MessageChat::Group(..) | MessageChat::Supergroup(..)),
                         ..
-                    } if user_id == &self.user_id && chat_id == &self.group_id => {
-                        let monitor_tx = if let Some(monitor_tx) = &mut self.maybe_monitor_tx {
-                            monitor_tx
-                        } else {
-                            let (monitor_tx, monitor_rx) = mpsc::channel(0);
-                            tokio::spawn(run_monitor(
-                                api.clone(),
-                                monitor_rx,
-                                self.group_id,
-                                self.forward_group_id,
-                                self.window_size,
-                                self.check_timeout_s,
-                            ));
-
-                            log::info!("monitor task has spawned");
-                            self.maybe_monitor_tx
-                                .get_or_insert(monitor_tx)
-                        };
-                        monitor_tx.send(message.clone()).await
+                    } if user_id == &self.user_id && chat.id() == self.chat_id => {
+                        let monitor_tx = self.maybe_monitor_tx.as_mut()
+                            .ok_or(Error::MonitorTaskIsGone)?;
+                        monitor_tx.send(WatchedMessage::new(message, update.maybe_message_thread_id)).await
                             .map_err(|_send_error| Error::MonitorTaskIsGone)?;
                     },
                     _other_message =>
@@ -143,23 +316,135 @@ impl DeleteRecover {
     }
 }
 
+impl From<Error> for bot_module::Error {
+    fn from(error: Error) -> bot_module::Error {
+        bot_module::Error::new(error)
+    }
+}
+
+#[async_trait::async_trait]
+impl bot_module::BotModule for DeleteRecover {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn init(&mut self, context: &bot_module::Context) -> Result<(), bot_module::Error> {
+        let chat_id: Integer = self.chat_id.into();
+        let mut window = self.window.lock().await;
+        if window.is_empty() {
+            let saved_window: Option<SavedWindow> = context.state_store.load(&self.state_key)
+                .map_err(Error::StateStore)?;
+            *window = match saved_window {
+                Some(saved_window) =>
+                    saved_window.messages,
+                None =>
+                    take_legacy_window(&context.state_store, &self.legacy_state_key, &self.state_key)?,
+            };
+        }
+        // messages of a migrated chat cannot be forwarded anymore
+        window.retain(|message| message.chat_id == chat_id);
+        while window.len() > self.window_size {
+            window.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn handle_update(&mut self, update: &bot_module::IncomingUpdate, _context: &bot_module::Context) -> Result<(), bot_module::Error> {
+        self.process(update).await?;
+        Ok(())
+    }
+
+    fn background_tasks(&mut self, context: &bot_module::Context) -> Vec<bot_module::BackgroundTask> {
+        // the monitor takes new messages only between window checks, which are slow
+        // under the send queue limits: buffer up to a window worth of messages
+        let (monitor_tx, monitor_rx) = mpsc::channel(self.window_size);
+        self.maybe_monitor_tx = Some(monitor_tx);
+        // a restarted monitor picks up the same receiver
+        let monitor_rx = Arc::new(Mutex::new(monitor_rx));
+        let client = context.client.clone();
+        let clock = context.clock.clone();
+        let window = self.window.clone();
+        let state_store = context.state_store.clone();
+        let state_key = self.state_key.clone();
+        let (chat_id, forward_chat_id, window_size, check_timeout_s) =
+            (self.chat_id, self.forward_chat_id, self.window_size, self.check_timeout_s);
+        vec![
+            bot_module::BackgroundTask {
+                name: "monitor".to_string(),
+                run: Box::new(move || {
+                    run_monitor(
+                        client.clone(),
+                        clock.clone(),
+                        monitor_rx.clone(),
+                        window.clone(),
+                        state_store.clone(),
+                        state_key.clone(),
+                        chat_id,
+                        forward_chat_id,
+                        window_size,
+                        check_timeout_s,
+                    ).boxed()
+                }),
+            },
+        ]
+    }
+
+    fn migrate_chat(&mut self, from: ChatId, to: ChatId) -> bool {
+        let mut migrated = false;
+        if self.chat_id == from {
+            self.chat_id = to;
+            migrated = true;
+        }
+        if self.forward_chat_id == from {
+            self.forward_chat_id = to;
+            migrated = true;
+        }
+        migrated
+    }
+
+    async fn shutdown(&mut self) -> Result<(), bot_module::Error> {
+        // dropping the sender makes the monitor task run the last check, save the window
+        // and terminate, it releases the window when done
+        self.maybe_monitor_tx = None;
+        let window = self.window.lock().await;
+        log::info!("{}: {} messages left in the window on shutdown", self.name, window.len());
+        Ok(())
+    }
+
+    fn take_state(&mut self) -> Option<bot_module::State> {
+        let mut window = self.window.try_lock().ok()?;
+        Some(Box::new(std::mem::take(&mut *window)))
+    }
+
+    fn restore_state(&mut self, state: bot_module::State) {
+        if let (Ok(messages), Ok(mut window)) = (state.downcast::<VecDeque<WatchedMessage>>(), self.window.try_lock()) {
+            *window = *messages;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_monitor(
-    api: Api,
-    monitor_rx: mpsc::Receiver<Message>,
-    group_id: GroupId,
-    forward_group_id: GroupId,
+    client: Arc<dyn TelegramClient>,
+    clock: Arc<dyn Clock>,
+    monitor_rx: Arc<Mutex<mpsc::Receiver<WatchedMessage>>>,
+    window: Window,
+    state_store: Arc<StateStore>,
+    state_key: String,
+    chat_id: ChatId,
+    forward_chat_id: ChatId,
     window_size: usize,
     check_timeout_s: u64,
 )
+    -> Result<(), bot_module::Error>
 {
-    let mut fused_monitor_rx = monitor_rx.fuse();
+    let mut window = window.lock_owned().await;
+    let mut monitor_rx = monitor_rx.lock_owned().await;
     let mut current_timeout = None;
-    let mut window = VecDeque::with_capacity(window_size);
-    let mut window_backup = Vec::with_capacity(window_size);
 
     loop {
         if current_timeout.is_none() {
-            current_timeout = Some(Box::pin(sleep(Duration::from_secs(check_timeout_s)).fuse()));
+            current_timeout = Some(clock.sleep(Duration::from_secs(check_timeout_s)).fuse());
         }
 
         enum Event<M> {
@@ -169,20 +454,22 @@ async fn run_monitor(
 
         let event = if let Some(mut sleep_future) = current_timeout.as_mut() {
             select! {
-                result = fused_monitor_rx.next() =>
+                result = monitor_rx.next() =>
                     Event::Message(result),
                 () = sleep_future =>
                     Event::MonitorTimeout,
             }
         } else {
-            Event::Message(fused_monitor_rx.next().await)
+            Event::Message(monitor_rx.next().await)
         };
 
         match event {
 
             Event::Message(None) => {
-                log::info!("monitor rx channel dropped: terminating");
-                break;
+                log::info!("monitor rx channel dropped: checking the window for the last time");
+                probe_window(client.as_ref(), &mut window, chat_id, forward_chat_id).await;
+                save_window(&state_store, &state_key, &window);
+                return Ok(());
             },
 
             Event::Message(Some(message)) => {
@@ -191,50 +478,345 @@ async fn run_monitor(
                     window.pop_front();
                 }
                 window.push_back(message);
+                save_window(&state_store, &state_key, &window);
             },
 
             Event::MonitorTimeout => {
                 current_timeout = None;
-                for message in window.drain(..) {
-                    let mut forward_message = message.forward(&forward_group_id);
-                    forward_message.disable_notification();
-                    match api.send(forward_message).await {
-                        Ok(message_or_channel_post) => {
-                            api.send(message_or_channel_post.delete()).await.ok();
-                            window_backup.push(message);
-                        },
-                        Err(error) if format!("{}", error).contains("message to forward not found") => {
-                            log::debug!("detected deleted message: {:?}", message);
-                            let who = if let Some(username) = &message.from.username {
-                                format!("@{}", username)
-                            } else {
-                                message.from.first_name.to_string()
-                            };
-                            let source = match &message.kind {
-                                MessageKind::Text { data, .. } |
-                                MessageKind::Document { caption: Some(data), .. } |
-                                MessageKind::Photo { caption: Some(data), .. } |
-                                MessageKind::Video { caption: Some(data), .. } =>
-                                    format!("\n```\n{}\n```\n", data),
-                                _ =>
-                                    String::new(),
-                            };
-                            let mut monitor_notify_message =
-                                SendMessage::new(&group_id, format!("{} , вот злодей, удалил сообщение!{}", who, source));
-                            monitor_notify_message.parse_mode(ParseMode::Markdown);
-                            api.send(monitor_notify_message).await.ok();
-                        },
-                        Err(error) => {
-                            log::error!("failed to forward: {:?}", error);
-                            break;
-                        },
-                    }
+                probe_window(client.as_ref(), &mut window, chat_id, forward_chat_id).await;
+                save_window(&state_store, &state_key, &window);
+            },
+
+        }
+
+
+    }
+}
+
+/// Moves a window saved under the group only key, from before instances were
+/// told apart by user, over to the instance key. The first instance of the
+/// group to start takes it.
+fn take_legacy_window(state_store: &StateStore, legacy_state_key: &str, state_key: &str) -> Result<VecDeque<WatchedMessage>, Error> {
+    let saved_window: Option<SavedWindow> = state_store.load(legacy_state_key)
+        .map_err(Error::StateStore)?;
+    let saved_window = match saved_window {
+        Some(saved_window) if !saved_window.messages.is_empty() =>
+            saved_window,
+        _ =>
+            return Ok(VecDeque::new()),
+    };
+    log::info!("moving window {:?} to {:?}", legacy_state_key, state_key);
+    state_store.save(state_key, &saved_window)
+        .map_err(Error::StateStore)?;
+    state_store.save(legacy_state_key, &SavedWindow::default())
+        .map_err(Error::StateStore)?;
+    Ok(saved_window.messages)
+}
+
+fn save_window(state_store: &StateStore, state_key: &str, window: &VecDeque<WatchedMessage>) {
+    let saved_window = SavedWindow { messages: window.clone(), };
+    if let Err(error) = state_store.save(state_key, &saved_window) {
+        log::error!("failed to save window {:?}: {:?}", state_key, error);
+    }
+}
+
+/// Checks every message of the window, deleted ones are reported and dropped.
+/// Probed messages stay in place, so an aborted check loses nothing.
+async fn probe_window(client: &dyn TelegramClient, window: &mut VecDeque<WatchedMessage>, chat_id: ChatId, forward_chat_id: ChatId) {
+    let chat_label = chat_id.to_string();
+    let mut index = 0;
+    while index < window.len() {
+        metrics::DELETE_RECOVER_PROBES.with_label_values(&[&chat_label]).inc();
+        match probe_message(client, &window[index], forward_chat_id).await {
+            Probe::Alive =>
+                index += 1,
+            Probe::Deleted => {
+                metrics::DELETE_RECOVER_DELETIONS.with_label_values(&[&chat_label]).inc();
+                if let Some(message) = window.remove(index) {
+                    log::debug!("detected deleted message: {:?}", message);
+                    notify_deleted(client, &message, chat_id).await;
                 }
-                window.extend(window_backup.drain(..));
             },
+            Probe::Failed(error) => {
+                metrics::DELETE_RECOVER_PROBE_FAILURES.with_label_values(&[&chat_label]).inc();
+                log::error!("failed to forward: {:?}", error);
+                break;
+            },
+        }
+    }
+}
+
+enum Probe {
+    Alive,
+    Deleted,
+    Failed(telegram_client::Error),
+}
+
+/// Forwards the message to the monitor chat (and deletes the copy there) to
+/// find out whether the original still exists.
+async fn probe_message(client: &dyn TelegramClient, message: &WatchedMessage, forward_chat_id: ChatId) -> Probe {
+    let forward_result = client.forward_message(
+        forward_chat_id,
+        ChatId::new(message.chat_id),
+        MessageId::new(message.message_id),
+        true,
+    ).await;
+    match forward_result {
+        Ok(forwarded_message_id) => {
+            client.delete_message(forward_chat_id, forwarded_message_id).await.ok();
+            Probe::Alive
+        },
+        Err(error) if error.is_message_to_forward_not_found() =>
+            Probe::Deleted,
+        Err(error) =>
+            Probe::Failed(error),
+    }
+}
+
+async fn notify_deleted(client: &dyn TelegramClient, message: &WatchedMessage, chat_id: ChatId) {
+    let source = match &message.maybe_text {
+        Some(text) =>
+            format!("\n```\n{}\n```\n", text),
+        None =>
+            String::new(),
+    };
+    let mut monitor_notify_message =
+        OutgoingMessage::new(chat_id, format!("{} , вот злодей, удалил сообщение!{}", message.author, source));
+    monitor_notify_message.maybe_parse_mode = Some(ParseMode::Markdown);
+    monitor_notify_message.maybe_message_thread_id = message.maybe_message_thread_id;
+    if let Some(reply_to_message_id) = message.reply_to_message_id {
+        let mut monitor_notify_reply = monitor_notify_message.clone();
+        monitor_notify_reply.maybe_reply_to = Some(MessageId::new(reply_to_message_id));
+        if client.send_message(monitor_notify_reply).await.is_ok() {
+            return;
+        }
+    }
+    client.send_message(monitor_notify_message).await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+        },
+        time::{
+            Duration,
+        },
+        collections::{
+            VecDeque,
+        },
+    };
+
+    use chrono::{
+        Utc,
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use futures::{
+        channel::{
+            mpsc,
+        },
+        SinkExt,
+    };
+
+    use tokio::{
+        sync::{
+            Mutex,
+        },
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            MessageId,
+        },
+    };
+
+    use super::{
+        run_monitor,
+        probe_window,
+        final_check_duration,
+        Error,
+        Config,
+        CliArgs,
+        SavedWindow,
+        DeleteRecover,
+        WatchedMessage,
+    };
+
+    use crate::{
+        bot_module::{
+            self,
+            Context,
+            Registry,
+            BotModule,
+        },
+        health::{
+            self,
+            Health,
+        },
+        clock::{
+            self,
+            VirtualClock,
+        },
+        state_store::{
+            StateStore,
+        },
+        telegram_client::{
+            fake::{
+                Call,
+                Method,
+                FakeClient,
+            },
+        },
+    };
 
+    fn watched(message_id: i64, reply_to_message_id: Option<i64>) -> WatchedMessage {
+        watched_in(-1, message_id, reply_to_message_id)
+    }
+
+    fn watched_in(chat_id: i64, message_id: i64, reply_to_message_id: Option<i64>) -> WatchedMessage {
+        WatchedMessage {
+            chat_id,
+            message_id,
+            reply_to_message_id,
+            maybe_message_thread_id: None,
+            author: "@villain".to_string(),
+            maybe_text: Some(format!("message {}", message_id)),
         }
+    }
+
+    #[tokio::test]
+    async fn deleted_message_is_reported_in_its_topic() {
+        let client = FakeClient::new();
+        let in_topic = WatchedMessage { maybe_message_thread_id: Some(4), ..watched(11, Some(5)) };
+        let mut window: VecDeque<_> = vec![in_topic, watched(10, None), watched(12, None)].into();
+        client.fail_next(Method::Forward, "Bad Request: message to forward not found");
+
+        probe_window(&client, &mut window, ChatId::new(-1), ChatId::new(-3)).await;
 
+        assert_eq!(window, vec![watched(10, None), watched(12, None)]);
+        // forwarded copies of the alive messages are removed from the monitor chat
+        let deleted_copies = client.calls().into_iter()
+            .filter(|call| matches!(call, Call::Delete { chat_id, .. } if *chat_id == ChatId::new(-3)))
+            .count();
+        assert_eq!(deleted_copies, 2);
+        let reports: Vec<_> = client.calls().into_iter()
+            .filter_map(|call| match call {
+                Call::Send(message) =>
+                    Some(message),
+                _ =>
+                    None,
+            })
+            .collect();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].chat_id, ChatId::new(-1));
+        assert_eq!(reports[0].maybe_reply_to, Some(MessageId::new(5)));
+        assert_eq!(reports[0].maybe_message_thread_id, Some(4));
+        assert!(reports[0].text.contains("@villain"));
+        assert!(reports[0].text.contains("message 11"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_is_probed_every_check_timeout() {
+        let client = Arc::new(FakeClient::new());
+        let (mut monitor_tx, monitor_rx) = mpsc::channel(4);
+        let monitor = tokio::spawn(run_monitor(
+            client.clone(),
+            Arc::new(VirtualClock::new(Utc::now())),
+            Arc::new(Mutex::new(monitor_rx)),
+            Arc::new(Mutex::new(VecDeque::new())),
+            Arc::new(StateStore::in_memory()),
+            "window".to_string(),
+            ChatId::new(-1),
+            ChatId::new(-3),
+            4,
+            60,
+        ));
+        monitor_tx.send(watched(10, None)).await.unwrap();
+        let forwards = || client.calls().into_iter().filter(|call| matches!(call, Call::Forward { .. })).count();
+
+        // checked half a second around every probe time
+        tokio::time::sleep(Duration::from_millis(59_500)).await;
+        assert_eq!(forwards(), 0);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(forwards(), 1);
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert_eq!(forwards(), 1);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(forwards(), 2);
+
+        drop(monitor_tx);
+        monitor.await.unwrap().unwrap();
+        assert_eq!(forwards(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reload_keeps_window_of_remaining_instance() {
+        let state_store = Arc::new(StateStore::in_memory());
+        let context = Context {
+            client: Arc::new(FakeClient::new()),
+            state_store: state_store.clone(),
+            clock: Arc::new(clock::SystemClock),
+        };
+        let health = Arc::new(Health::new(&health::CliArgs::parse_from(["test"]), true));
+        let mut registry = Registry::new(&bot_module::CliArgs::parse_from(["test"]), health);
+        let config = |group_id| Config { user_id: 7, group_id, forward_group_id: -3, window_size: 4, check_timeout_s: 60, };
+        let saved = |chat_id| SavedWindow { messages: vec![watched_in(chat_id, 10, None)].into(), };
+        state_store.save("delete_recover/-1/7/window", &saved(-1)).unwrap();
+        state_store.save("delete_recover/-2/7/window", &saved(-2)).unwrap();
+
+        registry.reload(&[config(-1), config(-2)], DeleteRecover::new, &context).await;
+        // the first instance is removed, the second one takes its position
+        registry.reload(&[config(-2)], DeleteRecover::new, &context).await;
+        registry.shutdown().await;
+
+        let window: SavedWindow = state_store.load("delete_recover/-2/7/window").unwrap().unwrap();
+        assert_eq!(window.messages, vec![watched_in(-2, 10, None)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn legacy_window_is_taken_once() {
+        let state_store = Arc::new(StateStore::in_memory());
+        let context = Context {
+            client: Arc::new(FakeClient::new()),
+            state_store: state_store.clone(),
+            clock: Arc::new(clock::SystemClock),
+        };
+        let config = |user_id| Config { user_id, group_id: -1, forward_group_id: -3, window_size: 4, check_timeout_s: 60, };
+        state_store.save("delete_recover/-1/window", &SavedWindow { messages: vec![watched_in(-1, 10, None)].into(), }).unwrap();
+
+        let mut first = DeleteRecover::new(&config(7));
+        first.init(&context).await.unwrap();
+        let mut second = DeleteRecover::new(&config(8));
+        second.init(&context).await.unwrap();
+
+        assert_eq!(*first.window.lock().await, vec![watched_in(-1, 10, None)]);
+        assert!(second.window.lock().await.is_empty());
+        let window: SavedWindow = state_store.load("delete_recover/-1/7/window").unwrap().unwrap();
+        assert_eq!(window.messages, vec![watched_in(-1, 10, None)]);
+    }
+
+    #[test]
+    fn final_check_takes_longest_for_shared_monitor_chat() {
+        let config = |group_id, forward_group_id, window_size| Config { user_id: 7, group_id, forward_group_id, window_size, check_timeout_s: 60, };
+        let configs = [config(-1, -3, 32), config(-2, -3, 8), config(-4, -5, 16)];
+        assert_eq!(final_check_duration(&configs, Duration::from_secs(3)), Duration::from_secs(120));
+        assert_eq!(final_check_duration(&[], Duration::from_secs(3)), Duration::ZERO);
+    }
 
+    #[test]
+    fn instances_differ_by_user() {
+        let config = |user_id| Config { user_id, group_id: -1, forward_group_id: -3, window_size: 4, check_timeout_s: 60, };
+        let cli_args = CliArgs::parse_from(["test"]);
+        assert!(cli_args.override_config(vec![config(7), config(8)]).is_ok());
+        assert!(matches!(
+            cli_args.override_config(vec![config(7), config(7)]),
+            Err(Error::DuplicateInstance { user_id: 7, group_id: -1, }),
+        ));
     }
 }
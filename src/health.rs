@@ -0,0 +1,198 @@
+use std::{
+    sync::{
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+    collections::{
+        BTreeMap,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use serde::{
+    Serialize,
+};
+
+pub const DEFAULT_HEALTH_STREAM_STALE_S_STR: &str = "120";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// polling stream is reported dead after this long without a successful `getUpdates` (in seconds)
+    #[clap(long = "health-stream-stale-s", default_value = DEFAULT_HEALTH_STREAM_STALE_S_STR)]
+    health_stream_stale_s: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    /// The last run failed or panicked, or the module is waiting for restart.
+    Restarting,
+    /// The task is done on its own.
+    Finished,
+}
+
+#[derive(Default)]
+struct State {
+    maybe_last_poll_at: Option<Instant>,
+    maybe_last_poll_error: Option<String>,
+    maybe_last_update_at: Option<DateTime<Utc>>,
+    tasks: BTreeMap<String, TaskStatus>,
+}
+
+/// Liveness of the update stream and of the module background tasks, served
+/// on `/healthz` and `/readyz`.
+pub struct Health {
+    maybe_stream_stale_after: Option<Duration>,
+    started_at: Instant,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamReport {
+    pub alive: bool,
+    #[serde(rename = "last_poll_s_ago")]
+    pub maybe_last_poll_s_ago: Option<u64>,
+    #[serde(rename = "last_error")]
+    pub maybe_last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// The update stream is alive: `/healthz` answers `200 OK`.
+    pub alive: bool,
+    /// Alive, polled at least once and all the background tasks are running:
+    /// `/readyz` answers `200 OK`.
+    pub ready: bool,
+    pub stream: StreamReport,
+    #[serde(rename = "last_update_at")]
+    pub maybe_last_update_at: Option<DateTime<Utc>>,
+    pub background_tasks: BTreeMap<String, TaskStatus>,
+}
+
+impl Health {
+    /// A webhook stream is never stale: the process exits when its server does.
+    pub fn new(cli_args: &CliArgs, polling: bool) -> Health {
+        Health {
+            maybe_stream_stale_after: if polling { Some(Duration::from_secs(cli_args.health_stream_stale_s)) } else { None },
+            started_at: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Successful `getUpdates` request, empty ones included.
+    pub fn stream_polled(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.maybe_last_poll_at = Some(Instant::now());
+        state.maybe_last_poll_error = None;
+    }
+
+    pub fn stream_failed(&self, error: String) {
+        self.state.lock().unwrap().maybe_last_poll_error = Some(error);
+    }
+
+    pub fn update_received(&self) {
+        self.state.lock().unwrap().maybe_last_update_at = Some(Utc::now());
+    }
+
+    pub fn task_started(&self, name: &str) {
+        self.state.lock().unwrap().tasks.insert(name.to_string(), TaskStatus::Running);
+    }
+
+    pub fn task_finished(&self, name: &str) {
+        if let Some(status) = self.state.lock().unwrap().tasks.get_mut(name) {
+            *status = TaskStatus::Finished;
+        }
+    }
+
+    /// The task failed or its module is stopped for a restart.
+    pub fn task_restarting(&self, name: &str) {
+        self.state.lock().unwrap().tasks.insert(name.to_string(), TaskStatus::Restarting);
+    }
+
+    /// The task is stopped by its module: it is not reported anymore.
+    pub fn task_stopped(&self, name: &str) {
+        self.state.lock().unwrap().tasks.remove(name);
+    }
+
+    pub fn report(&self) -> Report {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let stream_alive = match self.maybe_stream_stale_after {
+            Some(stale_after) =>
+                now.duration_since(state.maybe_last_poll_at.unwrap_or(self.started_at)) < stale_after,
+            None =>
+                true,
+        };
+        let polled = self.maybe_stream_stale_after.is_none() || state.maybe_last_poll_at.is_some();
+        let tasks_running = state.tasks.values().all(|status| *status == TaskStatus::Running);
+        Report {
+            alive: stream_alive,
+            ready: stream_alive && polled && tasks_running,
+            stream: StreamReport {
+                alive: stream_alive,
+                maybe_last_poll_s_ago: state.maybe_last_poll_at.map(|last_poll_at| now.duration_since(last_poll_at).as_secs()),
+                maybe_last_error: state.maybe_last_poll_error.clone(),
+            },
+            maybe_last_update_at: state.maybe_last_update_at,
+            background_tasks: state.tasks.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{
+        Parser,
+    };
+
+    use super::{
+        Health,
+        CliArgs,
+    };
+
+    #[test]
+    fn ready_needs_poll_and_running_tasks() {
+        let health = Health::new(&CliArgs::parse_from(["test"]), true);
+        health.task_started("module#0/task");
+        let report = health.report();
+        assert!(report.alive);
+        assert!(!report.ready);
+
+        health.stream_polled();
+        assert!(health.report().ready);
+
+        health.task_finished("module#0/task");
+        let report = health.report();
+        assert!(report.alive);
+        assert!(!report.ready);
+
+        health.task_stopped("module#0/task");
+        assert!(health.report().ready);
+    }
+
+    #[test]
+    fn polling_stream_goes_stale() {
+        let health = Health::new(&CliArgs::parse_from(["test", "--health-stream-stale-s", "0"]), true);
+        health.stream_polled();
+        let report = health.report();
+        assert!(!report.alive);
+        assert!(!report.ready);
+
+        let health = Health::new(&CliArgs::parse_from(["test", "--health-stream-stale-s", "0"]), false);
+        assert!(health.report().ready);
+    }
+}
@@ -1,80 +1,425 @@
 #![forbid(unsafe_code)]
 
 use std::{
+    fmt,
     sync::{
         Arc,
     },
-};
-
-use futures::{
-    StreamExt,
+    process::{
+        ExitCode,
+    },
 };
 
 use clap::{
     Parser,
+    ArgEnum,
+    Subcommand,
     AppSettings,
 };
 
+use tokio::{
+    signal::{
+        unix::{
+            signal,
+            Signal,
+            SignalKind,
+        },
+    },
+};
+
 use telegram_bot::{
-    Api,
+    types::{
+        Integer,
+    },
 };
 
+mod clock;
+mod config;
+mod polling;
+mod webhook;
+mod metrics;
+mod health;
+mod recorder;
+mod replay;
+mod bot_module;
+mod state_store;
+mod telegram_client;
+mod send_queue;
 mod vaccine_reminder;
 mod delete_recover;
-mod good_morning_darya;
+mod schedule;
+mod scheduler;
 
 #[derive(Clone, Debug, Parser)]
 #[clap(setting = AppSettings::DeriveDisplayOrder)]
 struct CliArgs {
-    /// facebook accounts database
-    #[clap(short = 't', long = "telegram-bot-token")]
-    telegram_bot_token: String,
+    #[clap(subcommand)]
+    maybe_command: Option<Command>,
+
+    /// how to receive updates
+    #[clap(long = "mode", arg_enum, default_value = "polling")]
+    mode: Mode,
+
+    /// print outgoing telegram requests to stdout as json lines instead of sending them, state file is not written and updates are not acknowledged on exit
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+
+    #[clap(flatten)]
+    config: config::CliArgs,
+
+    #[clap(flatten)]
+    polling: polling::CliArgs,
+
+    #[clap(flatten)]
+    webhook: webhook::CliArgs,
+
+    #[clap(flatten)]
+    send_queue: send_queue::CliArgs,
+
+    #[clap(flatten)]
+    metrics: metrics::CliArgs,
+
+    #[clap(flatten)]
+    health: health::CliArgs,
 
     #[clap(flatten)]
-    vaccine_reminder: vaccine_reminder::CliArgs,
+    recorder: recorder::CliArgs,
 
     #[clap(flatten)]
-    delete_recover: delete_recover::CliArgs,
+    modules: bot_module::CliArgs,
 
     #[clap(flatten)]
-    good_morning_darya: good_morning_darya::CliArgs,
+    state_store: state_store::CliArgs,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// feed recorded updates through the modules offline and print the requests they make
+    Replay(replay::CliArgs),
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum)]
+enum Mode {
+    /// long polling with `getUpdates`
+    Polling,
+    /// http(s) listener for updates pushed by Telegram
+    Webhook,
 }
 
 #[derive(Debug)]
 enum Error {
-    TelegramApiStream(telegram_bot::Error),
-    VaccineReminderCreate(vaccine_reminder::Error),
-    VaccineReminderProcess(vaccine_reminder::Error),
-    DeleteRecoverCreate(delete_recover::Error),
-    DeleteRecoverProcess(delete_recover::Error),
-    GoodMorningDaryaCreate(good_morning_darya::Error),
+    Config(config::Error),
+    StateStore(state_store::Error),
+    SignalHandler(std::io::Error),
+    Polling(polling::Error),
+    Webhook(webhook::Error),
+    Metrics(metrics::Error),
+    Recorder(recorder::Error),
+    Replay(replay::Error),
+    Runtime(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(error) =>
+                write!(f, "{}", error),
+            Error::StateStore(error) =>
+                write!(f, "state store failed: {:?}", error),
+            Error::SignalHandler(error) =>
+                write!(f, "cannot install signal handler: {}", error),
+            Error::Polling(error) =>
+                write!(f, "polling failed: {:?}", error),
+            Error::Webhook(error) =>
+                write!(f, "webhook failed: {:?}", error),
+            Error::Metrics(error) =>
+                write!(f, "metrics server failed: {:?}", error),
+            Error::Recorder(error) =>
+                write!(f, "recorder failed: {:?}", error),
+            Error::Replay(error) =>
+                write!(f, "replay failed: {:?}", error),
+            Error::Runtime(error) =>
+                write!(f, "cannot start tokio runtime: {}", error),
+        }
+    }
+}
+
+/// Signal streams, set up once for the whole run: a signal arriving while
+/// nobody waits for it is not lost.
+struct Signals {
+    hangup: Signal,
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl Signals {
+    fn new() -> Result<Signals, Error> {
+        Ok(Signals {
+            hangup: signal(SignalKind::hangup())
+                .map_err(Error::SignalHandler)?,
+            terminate: signal(SignalKind::terminate())
+                .map_err(Error::SignalHandler)?,
+            interrupt: signal(SignalKind::interrupt())
+                .map_err(Error::SignalHandler)?,
+        })
+    }
+}
+
+enum UpdateSource {
+    Polling(polling::Poller),
+    Webhook(webhook::Webhook),
+}
+
+impl UpdateSource {
+    async fn next_batch(&mut self) -> Result<Vec<polling::ReceivedUpdate>, Error> {
+        match self {
+            UpdateSource::Polling(poller) =>
+                poller.next_batch().await.map_err(Error::Polling),
+            UpdateSource::Webhook(webhook) =>
+                webhook.next_batch().await.map_err(Error::Webhook),
+        }
+    }
+
+    fn commit(&mut self, update_id: Integer) {
+        match self {
+            UpdateSource::Polling(poller) =>
+                poller.commit(update_id),
+            UpdateSource::Webhook(webhook) =>
+                webhook.commit(update_id),
+        }
+    }
+
+    /// Stops receiving updates, returns the ones received but not taken by
+    /// `next_batch` yet.
+    fn stop(&mut self) -> Vec<polling::ReceivedUpdate> {
+        match self {
+            UpdateSource::Polling(..) =>
+                Vec::new(),
+            UpdateSource::Webhook(webhook) =>
+                webhook.stop(),
+        }
+    }
+
+    /// Lets the server know which updates are processed before exit.
+    async fn acknowledge(&mut self) {
+        match self {
+            UpdateSource::Polling(poller) =>
+                if let Err(error) = poller.acknowledge().await {
+                    log::warn!("failed to acknowledge processed updates, some are going to be received again: {:?}", error);
+                },
+            UpdateSource::Webhook(..) =>
+                (),
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+fn main() -> ExitCode {
     pretty_env_logger::init_timed();
-    let cli_args = CliArgs::parse();
+    match run_command(CliArgs::parse()) {
+        Ok(()) =>
+            ExitCode::SUCCESS,
+        Err(error) => {
+            log::error!("{}", error);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run_command(cli_args: CliArgs) -> Result<(), Error> {
     log::debug!("cli_args = {:?}", cli_args);
+    match cli_args.maybe_command.clone() {
+        None => {
+            // `telegram_bot::Api` takes its base url from the environment, which is
+            // only safe to change before the runtime threads are started
+            let telegram_api_url = cli_args.polling.telegram_api_url();
+            if telegram_api_url != polling::DEFAULT_TELEGRAM_API_URL {
+                std::env::set_var("TELEGRAM_API_URL", format!("{}/", telegram_api_url.trim_end_matches('/')));
+            }
+            let runtime = tokio::runtime::Runtime::new()
+                .map_err(Error::Runtime)?;
+            runtime.block_on(run(cli_args))
+        },
+        Some(Command::Replay(replay_args)) => {
+            // virtual clock: paused time jumps to the next timer whenever the modules are idle
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .start_paused(true)
+                .build()
+                .map_err(Error::Runtime)?;
+            runtime.block_on(run_replay(&cli_args, &replay_args))
+        },
+    }
+}
+
+async fn run(cli_args: CliArgs) -> Result<(), Error> {
+    let config = config::load(&cli_args.config)
+        .map_err(Error::Config)?;
 
-    let api = Arc::new(Api::new(cli_args.telegram_bot_token));
+    let client: Arc<dyn telegram_client::TelegramClient> = if cli_args.dry_run {
+        log::warn!("dry run: outgoing requests are printed as json lines and not sent");
+        if matches!(cli_args.mode, Mode::Polling) {
+            log::warn!("dry run: polling takes updates away from the live bot, do not run it alongside one");
+        }
+        Arc::new(telegram_client::DryRunClient::new())
+    } else {
+        Arc::new(telegram_client::ApiClient::new(&config.telegram_bot_token, cli_args.polling.telegram_api_url()))
+    };
+    let send_queue = send_queue::SendQueue::new(client, &cli_args.send_queue);
+    let mut state_store = state_store::StateStore::open(&cli_args.state_store)
+        .map_err(Error::StateStore)?;
+    if cli_args.dry_run {
+        state_store = state_store.into_read_only();
+    }
+    let context = bot_module::Context {
+        client: Arc::new(send_queue),
+        state_store: Arc::new(state_store),
+        clock: Arc::new(clock::SystemClock),
+    };
 
-    let mut vaccine_reminder = vaccine_reminder::VaccineReminder::new(&cli_args.vaccine_reminder)
-        .map_err(Error::VaccineReminderCreate)?;
-    let mut delete_recover = delete_recover::DeleteRecover::new(&cli_args.delete_recover)
-        .map_err(Error::DeleteRecoverCreate)?;
-    let mut _good_morning_darya = good_morning_darya::GoodMorningDarya::new(api.clone(), &cli_args.good_morning_darya)
-        .map_err(Error::GoodMorningDaryaCreate)?;
+    let health = Arc::new(health::Health::new(&cli_args.health, matches!(cli_args.mode, Mode::Polling)));
+    let _maybe_metrics_server = metrics::MetricsServer::start(&cli_args.metrics, health.clone())
+        .map_err(Error::Metrics)?;
 
-    let mut stream = api.stream();
-    while let Some(update) = stream.next().await {
-        let update = update
-            .map_err(Error::TelegramApiStream)?;
+    let mut registry = bot_module::Registry::new(&cli_args.modules, health.clone());
+    apply_config(&cli_args, &config, &mut registry, &context).await;
 
-        vaccine_reminder.process(&update, &api).await
-            .map_err(Error::VaccineReminderProcess)?;
-        delete_recover.process(&update, &api).await
-            .map_err(Error::DeleteRecoverProcess)?;
+    let mut update_source = match cli_args.mode {
+        Mode::Polling =>
+            UpdateSource::Polling(polling::Poller::new(&config.telegram_bot_token, &cli_args.polling, health.clone())),
+        Mode::Webhook => {
+            let webhook = webhook::Webhook::start(&config.telegram_bot_token, cli_args.polling.telegram_api_url(), &cli_args.webhook, cli_args.dry_run).await
+                .map_err(Error::Webhook)?;
+            UpdateSource::Webhook(webhook)
+        },
+    };
+    let mut maybe_recorder = recorder::Recorder::open(&cli_args.recorder)
+        .map_err(Error::Recorder)?;
+    let mut signals = Signals::new()?;
+    let result = run_updates_loop(&cli_args, config, &mut signals, &mut update_source, &mut maybe_recorder, &mut registry, &context, &health).await;
+    let shutdown = async {
+        // updates the webhook has already accepted are not going to be delivered again
+        for received in update_source.stop() {
+            dispatch_received(received, &mut update_source, &mut maybe_recorder, &mut registry, &context, &health).await;
+        }
+        // modules finish their queued updates before those are acknowledged
+        registry.shutdown().await;
+        if !cli_args.dry_run {
+            update_source.acknowledge().await;
+        }
+    };
+    tokio::select! {
+        () = shutdown =>
+            log::info!("shutdown complete"),
+        Some(()) = signals.terminate.recv() =>
+            log::warn!("SIGTERM received again: exiting without waiting for the modules"),
+        Some(()) = signals.interrupt.recv() =>
+            log::warn!("SIGINT received again: exiting without waiting for the modules"),
     }
+    result
+}
 
+async fn run_replay(cli_args: &CliArgs, replay_args: &replay::CliArgs) -> Result<(), Error> {
+    let config = config::load_offline(&cli_args.config)
+        .map_err(Error::Config)?;
+    let recording = replay::Recording::read(replay_args)
+        .map_err(Error::Replay)?;
+    let clock = recording.clock();
+    let client = Arc::new(replay::ReplayClient::new(clock));
+    let context = bot_module::Context {
+        client: Arc::new(send_queue::SendQueue::new(client.clone(), &cli_args.send_queue)),
+        state_store: Arc::new(state_store::StateStore::in_memory()),
+        clock: Arc::new(clock),
+    };
+    let health = Arc::new(health::Health::new(&cli_args.health, false));
+    let mut registry = bot_module::Registry::new(&cli_args.modules, health);
+    apply_config(cli_args, &config, &mut registry, &context).await;
+
+    replay::run(recording, replay_args, &clock, &mut registry, &context).await;
+    registry.shutdown().await;
+    log::info!("replay complete: {} requests made", client.calls().len());
     Ok(())
 }
+
+/// Module registration: starts, restarts or stops module instances to match the config.
+async fn apply_config(cli_args: &CliArgs, config: &config::Config, registry: &mut bot_module::Registry, context: &bot_module::Context) {
+    registry.fit_shutdown_timeout(delete_recover::final_check_duration(&config.delete_recover, cli_args.send_queue.chat_interval()));
+    registry.reload(&config.vaccine_reminder, vaccine_reminder::VaccineReminder::new, context).await;
+    registry.reload(&config.delete_recover, delete_recover::DeleteRecover::new, context).await;
+    registry.reload(&scheduler::enabled(&config.scheduler), scheduler::Scheduler::new, context).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_updates_loop(
+    cli_args: &CliArgs,
+    mut config: config::Config,
+    signals: &mut Signals,
+    update_source: &mut UpdateSource,
+    maybe_recorder: &mut Option<recorder::Recorder>,
+    registry: &mut bot_module::Registry,
+    context: &bot_module::Context,
+    health: &health::Health,
+)
+    -> Result<(), Error>
+{
+    loop {
+        let restart_due = registry.restart_due();
+        tokio::select! {
+            () = restart_due =>
+                registry.restart_failed(context).await,
+            result = update_source.next_batch() => {
+                for received in result? {
+                    dispatch_received(received, update_source, maybe_recorder, registry, context, health).await;
+                }
+            },
+            Some(()) = signals.hangup.recv() =>
+                reload_config(cli_args, &mut config, registry, context).await,
+            Some(()) = signals.terminate.recv() => {
+                log::info!("SIGTERM received: shutting down");
+                return Ok(());
+            },
+            Some(()) = signals.interrupt.recv() => {
+                log::info!("SIGINT received: shutting down");
+                return Ok(());
+            },
+        }
+    }
+}
+
+async fn dispatch_received(
+    received: polling::ReceivedUpdate,
+    update_source: &mut UpdateSource,
+    maybe_recorder: &mut Option<recorder::Recorder>,
+    registry: &mut bot_module::Registry,
+    context: &bot_module::Context,
+    health: &health::Health,
+)
+{
+    let polling::ReceivedUpdate { update, raw, } = received;
+    if let Some(recorder) = maybe_recorder {
+        if let Err(error) = recorder.record(&raw) {
+            log::error!("failed to record update {}: {:?}", update.id, error);
+        }
+    }
+    metrics::UPDATES_RECEIVED.with_label_values(&[metrics::update_kind(&update)]).inc();
+    health.update_received();
+    let update_id = update.id;
+    registry.dispatch(bot_module::IncomingUpdate::new(update, &raw), context).await;
+    update_source.commit(update_id);
+}
+
+async fn reload_config(cli_args: &CliArgs, config: &mut config::Config, registry: &mut bot_module::Registry, context: &bot_module::Context) {
+    log::info!("SIGHUP received: reloading config");
+    let new_config = match config::load(&cli_args.config) {
+        Ok(new_config) =>
+            new_config,
+        Err(error) => {
+            log::error!("config reload failed, keeping the current one: {}", error);
+            return;
+        },
+    };
+    if new_config.telegram_bot_token != config.telegram_bot_token {
+        log::warn!("telegram bot token change requires a restart, ignoring it");
+    }
+    apply_config(cli_args, &new_config, registry, context).await;
+    *config = new_config;
+}
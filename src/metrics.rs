@@ -0,0 +1,306 @@
+use std::{
+    convert::Infallible,
+    net::{
+        SocketAddr,
+    },
+    sync::{
+        Arc,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use hyper::{
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    header,
+    Body,
+    Method,
+    Server,
+    Request,
+    Response,
+    StatusCode,
+};
+
+use prometheus::{
+    Encoder,
+    GaugeVec,
+    IntGaugeVec,
+    HistogramVec,
+    IntCounterVec,
+    TextEncoder,
+};
+
+use tokio::{
+    task::{
+        JoinHandle,
+    },
+};
+
+use telegram_bot::{
+    Update,
+    UpdateKind,
+};
+
+use crate::{
+    health::{
+        Health,
+    },
+};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// address to serve prometheus `/metrics`, `/healthz` and `/readyz` on (disabled if omitted)
+    #[clap(long = "metrics-listen-addr")]
+    metrics_listen_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Bind { addr: SocketAddr, error: hyper::Error, },
+}
+
+lazy_static::lazy_static! {
+    pub static ref UPDATES_RECEIVED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_updates_received_total",
+        "Updates received from Telegram by kind.",
+        &["kind"],
+    ).unwrap();
+
+    pub static ref MODULE_HANDLE_DURATION: HistogramVec = prometheus::register_histogram_vec!(
+        "beercan_module_handle_duration_seconds",
+        "Time a module spends handling an update.",
+        &["module"],
+    ).unwrap();
+
+    pub static ref MODULE_ERRORS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_module_errors_total",
+        "Updates a module failed to handle.",
+        &["module"],
+    ).unwrap();
+
+    pub static ref MODULE_QUEUE_DEPTH: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "beercan_module_queue_depth",
+        "Updates queued for a module.",
+        &["module"],
+    ).unwrap();
+
+    pub static ref BACKGROUND_TASK_RESTARTS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_background_task_restarts_total",
+        "Module background task runs which failed or panicked and were restarted.",
+        &["task"],
+    ).unwrap();
+
+    pub static ref TELEGRAM_REQUESTS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_telegram_requests_total",
+        "Requests to the Telegram Bot API by method and result, `sendMessage` ones are the messages sent.",
+        &["method", "result"],
+    ).unwrap();
+
+    pub static ref SEND_QUEUE_DEPTH: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "beercan_send_queue_depth",
+        "Requests waiting in the send queue by priority.",
+        &["priority"],
+    ).unwrap();
+
+    pub static ref DELETE_RECOVER_PROBES: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_delete_recover_probes_total",
+        "Watched messages checked for deletion.",
+        &["chat"],
+    ).unwrap();
+
+    pub static ref DELETE_RECOVER_DELETIONS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_delete_recover_deletions_total",
+        "Deleted messages detected.",
+        &["chat"],
+    ).unwrap();
+
+    pub static ref DELETE_RECOVER_PROBE_FAILURES: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_delete_recover_probe_failures_total",
+        "Message checks which failed with an unexpected error.",
+        &["chat"],
+    ).unwrap();
+
+    pub static ref SCHEDULER_PLANNED: GaugeVec = prometheus::register_gauge_vec!(
+        "beercan_scheduler_planned_timestamp_seconds",
+        "Unix time the last scheduled run was planned for.",
+        &["task"],
+    ).unwrap();
+
+    pub static ref SCHEDULER_FIRED: GaugeVec = prometheus::register_gauge_vec!(
+        "beercan_scheduler_fired_timestamp_seconds",
+        "Unix time the last scheduled run actually fired at.",
+        &["task"],
+    ).unwrap();
+
+    pub static ref SCHEDULER_SKIPPED: IntCounterVec = prometheus::register_int_counter_vec!(
+        "beercan_scheduler_skipped_runs_total",
+        "Scheduled runs found later than the grace window (host suspend, wall clock step) and skipped.",
+        &["task"],
+    ).unwrap();
+
+    pub static ref SCHEDULER_DELAY: HistogramVec = prometheus::register_histogram_vec!(
+        "beercan_scheduler_fire_delay_seconds",
+        "How late scheduled runs fire compared to the planned time.",
+        &["task"],
+    ).unwrap();
+}
+
+/// Label of the update kind for `UPDATES_RECEIVED`.
+pub fn update_kind(update: &Update) -> &'static str {
+    match &update.kind {
+        UpdateKind::Message(..) =>
+            "message",
+        UpdateKind::EditedMessage(..) =>
+            "edited_message",
+        UpdateKind::ChannelPost(..) =>
+            "channel_post",
+        UpdateKind::EditedChannelPost(..) =>
+            "edited_channel_post",
+        UpdateKind::InlineQuery(..) =>
+            "inline_query",
+        UpdateKind::CallbackQuery(..) =>
+            "callback_query",
+        UpdateKind::Error(..) =>
+            "error",
+        _ =>
+            "unknown",
+    }
+}
+
+/// Http server exposing the default prometheus registry and the health
+/// report, stopped on drop.
+pub struct MetricsServer {
+    server_task: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Returns `None` if no listen address is configured.
+    pub fn start(cli_args: &CliArgs, health: Arc<Health>) -> Result<Option<MetricsServer>, Error> {
+        let addr = match cli_args.metrics_listen_addr {
+            Some(addr) =>
+                addr,
+            None =>
+                return Ok(None),
+        };
+        let builder = Server::try_bind(&addr)
+            .map_err(|error| Error::Bind { addr, error, })?;
+        let server = builder.serve(make_service_fn(move |_connection| {
+            let health = health.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = handle(request, &health);
+                    async { Ok::<_, Infallible>(response) }
+                }))
+            }
+        }));
+        log::info!("serving metrics and health on http://{}", addr);
+        let server_task = tokio::spawn(async move {
+            if let Err(error) = server.await {
+                log::error!("metrics server terminated: {:?}", error);
+            }
+        });
+        Ok(Some(MetricsServer { server_task, }))
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.server_task.abort();
+    }
+}
+
+fn handle(request: Request<Body>, health: &Health) -> Response<Body> {
+    let path = request.uri().path();
+    if !["/metrics", "/healthz", "/readyz"].contains(&path) {
+        return reply(StatusCode::NOT_FOUND, "text/plain", Vec::new());
+    }
+    if request.method() != Method::GET {
+        return reply(StatusCode::METHOD_NOT_ALLOWED, "text/plain", Vec::new());
+    }
+    match path {
+        "/healthz" | "/readyz" => {
+            let report = health.report();
+            let ok = if path == "/healthz" { report.alive } else { report.ready };
+            let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            reply(status, "application/json", serde_json::to_vec(&report).unwrap_or_default())
+        },
+        _ =>
+            metrics(),
+    }
+}
+
+fn metrics() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) =>
+            reply(StatusCode::OK, encoder.format_type(), buffer),
+        Err(error) => {
+            log::error!("failed to encode metrics: {:?}", error);
+            reply(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", Vec::new())
+        },
+    }
+}
+
+fn reply(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{
+        body,
+        Body,
+        Method,
+        Request,
+        StatusCode,
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use super::{
+        handle,
+        TELEGRAM_REQUESTS,
+    };
+
+    use crate::{
+        health::{
+            self,
+            Health,
+        },
+    };
+
+    #[tokio::test]
+    async fn metrics_and_health_are_served() {
+        let health = Health::new(&health::CliArgs::parse_from(["test"]), true);
+        TELEGRAM_REQUESTS.with_label_values(&["sendMessage", "ok"]).inc();
+
+        let request = Request::builder().method(Method::GET).uri("/metrics").body(Body::empty()).unwrap();
+        let response = handle(request, &health);
+        assert_eq!(response.status(), StatusCode::OK);
+        let text = String::from_utf8(body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+        assert!(text.contains(r#"beercan_telegram_requests_total{method="sendMessage",result="ok"}"#));
+
+        let request = Request::builder().method(Method::GET).uri("/other").body(Body::empty()).unwrap();
+        assert_eq!(handle(request, &health).status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder().method(Method::GET).uri("/healthz").body(Body::empty()).unwrap();
+        assert_eq!(handle(request, &health).status(), StatusCode::OK);
+        // not polled yet
+        let request = Request::builder().method(Method::GET).uri("/readyz").body(Body::empty()).unwrap();
+        assert_eq!(handle(request, &health).status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    sync::{
+        Arc,
+    },
+    time::{
+        Duration,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use rand::Rng;
+
+use hyper::{
+    client::{
+        HttpConnector,
+    },
+    header,
+    Body,
+    Client,
+    Request,
+};
+
+use hyper_tls::{
+    HttpsConnector,
+};
+
+use serde::{
+    Deserialize,
+};
+
+use telegram_bot::{
+    types::{
+        Integer,
+    },
+    Update,
+};
+
+use crate::{
+    health::{
+        Health,
+    },
+};
+
+pub const DEFAULT_TELEGRAM_API_URL: &str = "https://api.telegram.org/";
+pub const DEFAULT_POLL_TIMEOUT_S_STR: &str = "30";
+pub const DEFAULT_RECONNECT_BACKOFF_MIN_MS_STR: &str = "500";
+pub const DEFAULT_RECONNECT_BACKOFF_MAX_S_STR: &str = "60";
+
+/// Extra time given to the http request on top of the long polling timeout.
+const REQUEST_TIMEOUT_GAP: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// telegram bot api base url (a local bot api server or a fake one in tests)
+    #[clap(long = "telegram-api-url", default_value = DEFAULT_TELEGRAM_API_URL)]
+    telegram_api_url: String,
+
+    /// long polling timeout for `getUpdates` (in seconds)
+    #[clap(long = "poll-timeout-s", default_value = DEFAULT_POLL_TIMEOUT_S_STR)]
+    poll_timeout_s: u64,
+
+    /// initial delay before reconnecting after a transient polling error (in milliseconds)
+    #[clap(long = "reconnect-backoff-min-ms", default_value = DEFAULT_RECONNECT_BACKOFF_MIN_MS_STR)]
+    reconnect_backoff_min_ms: u64,
+
+    /// maximum delay before reconnecting after a transient polling error (in seconds)
+    #[clap(long = "reconnect-backoff-max-s", default_value = DEFAULT_RECONNECT_BACKOFF_MAX_S_STR)]
+    reconnect_backoff_max_s: u64,
+}
+
+impl CliArgs {
+    pub fn telegram_api_url(&self) -> &str {
+        &self.telegram_api_url
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    RequestBuild(hyper::http::Error),
+    RequestTimeout,
+    Transport(hyper::Error),
+    ResponseBody(hyper::Error),
+    ResponseDecode(serde_json::Error),
+    Api {
+        error_code: Integer,
+        description: String,
+        retry_after: Option<u64>,
+    },
+}
+
+impl Error {
+    /// Transient errors are retried with backoff, all the others stop polling.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::RequestBuild(..) =>
+                false,
+            Error::RequestTimeout |
+            Error::Transport(..) |
+            Error::ResponseBody(..) |
+            Error::ResponseDecode(..) =>
+                true,
+            // 401 / 404 mean a bad token, 409 means a webhook is set or another instance is polling
+            Error::Api { error_code, .. } =>
+                *error_code == 429 || *error_code >= 500,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Api { retry_after: Some(retry_after), .. } =>
+                Some(Duration::from_secs(*retry_after)),
+            _ =>
+                None,
+        }
+    }
+}
+
+/// Decoded update along with the json it was received as.
+#[derive(Clone, Debug)]
+pub struct ReceivedUpdate {
+    pub update: Update,
+    pub raw: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct ResponseParameters {
+    retry_after: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    result: Option<Vec<serde_json::Value>>,
+    error_code: Option<Integer>,
+    description: Option<String>,
+    parameters: Option<ResponseParameters>,
+}
+
+/// Long polling `getUpdates` loop which survives transient failures. Updates
+/// are acknowledged with `commit` after processing, so a reconnect neither
+/// loses nor repeats them.
+pub struct Poller {
+    client: Client<HttpsConnector<HttpConnector>>,
+    get_updates_url: String,
+    poll_timeout: Duration,
+    reconnect_backoff_min: Duration,
+    reconnect_backoff_max: Duration,
+    maybe_last_update_id: Option<Integer>,
+    health: Arc<Health>,
+}
+
+impl Poller {
+    /// Every `getUpdates` outcome is reported to `health`.
+    pub fn new(telegram_bot_token: &str, cli_args: &CliArgs, health: Arc<Health>) -> Poller {
+        let reconnect_backoff_min = Duration::from_millis(cli_args.reconnect_backoff_min_ms);
+        Poller {
+            client: Client::builder()
+                .build(HttpsConnector::new()),
+            get_updates_url: method_url(&cli_args.telegram_api_url, telegram_bot_token, "getUpdates"),
+            poll_timeout: Duration::from_secs(cli_args.poll_timeout_s),
+            reconnect_backoff_min,
+            reconnect_backoff_max: Duration::from_secs(cli_args.reconnect_backoff_max_s)
+                .max(reconnect_backoff_min),
+            maybe_last_update_id: None,
+            health,
+        }
+    }
+
+    /// Marks the update as processed.
+    pub fn commit(&mut self, update_id: Integer) {
+        match self.maybe_last_update_id {
+            Some(last_update_id) if last_update_id >= update_id =>
+                (),
+            _ =>
+                self.maybe_last_update_id = Some(update_id),
+        }
+    }
+
+    /// Waits for the next non-empty batch of updates, reconnecting on transient errors.
+    pub async fn next_batch(&mut self) -> Result<Vec<ReceivedUpdate>, Error> {
+        let mut attempt = 0;
+        loop {
+            let result = self.get_updates(self.poll_timeout).await;
+            match &result {
+                Ok(..) =>
+                    self.health.stream_polled(),
+                Err(error) =>
+                    self.health.stream_failed(format!("{:?}", error)),
+            }
+            match result {
+                Ok(updates) if updates.is_empty() => {
+                    attempt = 0;
+                },
+                Ok(updates) =>
+                    return Ok(updates),
+                Err(error) if error.is_transient() => {
+                    let delay = backoff_delay(attempt, self.reconnect_backoff_min, self.reconnect_backoff_max, &mut rand::thread_rng())
+                        .max(error.retry_after().unwrap_or_default());
+                    log::warn!("polling failed (attempt {}), reconnecting in {:?}: {:?}", attempt + 1, delay, error);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                Err(error) => {
+                    log::error!("polling failed with fatal error: {:?}", error);
+                    return Err(error);
+                },
+            }
+        }
+    }
+
+    /// Confirms committed updates to the server, so they are not received again
+    /// after a restart. Updates returned by this call are left unconfirmed.
+    pub async fn acknowledge(&mut self) -> Result<(), Error> {
+        if self.maybe_last_update_id.is_some() {
+            self.get_updates(Duration::ZERO).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_updates(&mut self, poll_timeout: Duration) -> Result<Vec<ReceivedUpdate>, Error> {
+        let mut params = serde_json::json!({ "timeout": poll_timeout.as_secs() });
+        if let Some(last_update_id) = self.maybe_last_update_id {
+            params["offset"] = (last_update_id + 1).into();
+        }
+        let request = Request::post(&self.get_updates_url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(params.to_string()))
+            .map_err(Error::RequestBuild)?;
+
+        let response = tokio::time::timeout(poll_timeout + REQUEST_TIMEOUT_GAP, self.client.request(request)).await
+            .map_err(|_elapsed| Error::RequestTimeout)?
+            .map_err(Error::Transport)?;
+        let body = hyper::body::to_bytes(response.into_body()).await
+            .map_err(Error::ResponseBody)?;
+        let response: Response = serde_json::from_slice(&body)
+            .map_err(Error::ResponseDecode)?;
+
+        if !response.ok {
+            return Err(Error::Api {
+                error_code: response.error_code.unwrap_or_default(),
+                description: response.description.unwrap_or_default(),
+                retry_after: response.parameters.and_then(|parameters| parameters.retry_after),
+            });
+        }
+
+        let mut updates = Vec::new();
+        for value in response.result.unwrap_or_default() {
+            let update_id = value.get("update_id").and_then(serde_json::Value::as_i64);
+            if let (Some(update_id), Some(last_update_id)) = (update_id, self.maybe_last_update_id) {
+                if update_id <= last_update_id {
+                    log::debug!("skipping already processed update {}", update_id);
+                    continue;
+                }
+            }
+            match serde_json::from_value(value.clone()) {
+                Ok(update) =>
+                    updates.push(ReceivedUpdate { update, raw: value, }),
+                Err(error) => {
+                    log::error!("failed to decode update {:?}: {:?}", update_id, error);
+                    if let Some(update_id) = update_id {
+                        self.commit(update_id);
+                    }
+                },
+            }
+        }
+        Ok(updates)
+    }
+}
+
+pub fn method_url(telegram_api_url: &str, telegram_bot_token: &str, method: &str) -> String {
+    format!("{}/bot{}/{}", telegram_api_url.trim_end_matches('/'), telegram_bot_token, method)
+}
+
+/// Exponential backoff with "equal jitter": a random delay in `[d / 2, d]`.
+fn backoff_delay<R>(attempt: u32, backoff_min: Duration, backoff_max: Duration, rng: &mut R) -> Duration where R: Rng {
+    let delay = backoff_min
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(backoff_max)
+        .min(backoff_max);
+    let half = delay / 2;
+    half + half.mul_f64(rng.gen_range(0.0 ..= 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        time::{
+            Duration,
+        },
+    };
+
+    use super::{
+        Error,
+        method_url,
+        backoff_delay,
+    };
+
+    fn api_error(error_code: i64) -> Error {
+        Error::Api { error_code, description: String::new(), retry_after: None, }
+    }
+
+    #[test]
+    fn classify_errors() {
+        assert!(Error::RequestTimeout.is_transient());
+        assert!(api_error(429).is_transient());
+        assert!(api_error(502).is_transient());
+        assert!(!api_error(401).is_transient());
+        assert!(!api_error(404).is_transient());
+        assert!(!api_error(409).is_transient());
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let mut rng = rand::thread_rng();
+        let min = Duration::from_millis(500);
+        let max = Duration::from_secs(60);
+        for _ in 0 .. 100 {
+            let delay = backoff_delay(0, min, max, &mut rng);
+            assert!(delay >= min / 2 && delay <= min);
+            let delay = backoff_delay(3, min, max, &mut rng);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+            let delay = backoff_delay(40, min, max, &mut rng);
+            assert!(delay >= max / 2 && delay <= max);
+        }
+    }
+
+    #[test]
+    fn method_url_trailing_slash() {
+        assert_eq!(method_url("https://api.telegram.org/", "T", "getUpdates"), "https://api.telegram.org/botT/getUpdates");
+        assert_eq!(method_url("http://127.0.0.1:8081", "T", "getUpdates"), "http://127.0.0.1:8081/botT/getUpdates");
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    io::{
+        self,
+        Write,
+    },
+    fs::{
+        self,
+        File,
+        OpenOptions,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use chrono::{
+    Utc,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use telegram_bot::{
+    types::{
+        Integer,
+    },
+};
+
+pub const DEFAULT_RECORD_MAX_BYTES_STR: &str = "67108864";
+pub const DEFAULT_RECORD_MAX_FILES_STR: &str = "5";
+
+/// Placeholder for redacted string values.
+const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// jsonl file to append every received update to (recorder is disabled if omitted)
+    #[clap(long = "record-file")]
+    record_file: Option<PathBuf>,
+
+    /// record file is rotated when it grows over this size (in bytes)
+    #[clap(long = "record-max-bytes", default_value = DEFAULT_RECORD_MAX_BYTES_STR)]
+    record_max_bytes: u64,
+
+    /// number of rotated record files to keep (`<record-file>.1` is the newest one)
+    #[clap(long = "record-max-files", default_value = DEFAULT_RECORD_MAX_FILES_STR)]
+    record_max_files: usize,
+
+    /// record updates from this chat only (may be repeated, all chats if omitted)
+    #[clap(long = "record-chat-id", allow_hyphen_values = true, multiple_occurrences = true)]
+    record_chat_ids: Vec<Integer>,
+
+    /// json field to redact at any depth, e.g. `text` or `username` (may be repeated)
+    #[clap(long = "record-redact", multiple_occurrences = true)]
+    record_redact: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Open { path: PathBuf, error: io::Error, },
+    Write { path: PathBuf, error: io::Error, },
+    Rotate { path: PathBuf, error: io::Error, },
+}
+
+/// Appends raw updates as `{"received_at": ..., "update": ...}` json lines:
+/// a corpus of real traffic to debug the modules against.
+pub struct Recorder {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+    chat_ids: Vec<Integer>,
+    redact: Vec<String>,
+}
+
+impl Recorder {
+    /// Returns `None` if no record file is configured.
+    pub fn open(cli_args: &CliArgs) -> Result<Option<Recorder>, Error> {
+        let path = match &cli_args.record_file {
+            Some(path) =>
+                path.clone(),
+            None =>
+                return Ok(None),
+        };
+        let file = open_append(&path)?;
+        let size = file.metadata()
+            .map_err(|error| Error::Open { path: path.clone(), error, })?
+            .len();
+        log::info!("recording updates to {:?}", path);
+        Ok(Some(Recorder {
+            path,
+            file,
+            size,
+            max_bytes: cli_args.record_max_bytes,
+            max_files: cli_args.record_max_files,
+            chat_ids: cli_args.record_chat_ids.clone(),
+            redact: cli_args.record_redact.clone(),
+        }))
+    }
+
+    pub fn record(&mut self, raw_update: &Value) -> Result<(), Error> {
+        if !self.chat_ids.is_empty() && !update_chat_id(raw_update).is_some_and(|chat_id| self.chat_ids.contains(&chat_id)) {
+            return Ok(());
+        }
+        let mut update = raw_update.clone();
+        for field in &self.redact {
+            redact(&mut update, field);
+        }
+        let mut line = json!({ "received_at": Utc::now(), "update": update, }).to_string();
+        line.push('\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())
+            .map_err(|error| Error::Write { path: self.path.clone(), error, })?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `<path>.N-1` becomes `<path>.N` and so on, the current file becomes `<path>.1`.
+    fn rotate(&mut self) -> Result<(), Error> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)
+                .map_err(|error| Error::Rotate { path: self.path.clone(), error, })?;
+        } else {
+            for index in (1 .. self.max_files).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))
+                        .map_err(|error| Error::Rotate { path: from, error, })?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))
+                .map_err(|error| Error::Rotate { path: self.path.clone(), error, })?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        log::debug!("record file {:?} is rotated", self.path);
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| Error::Open { path: path.to_path_buf(), error, })
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+/// Chat of the message the update is about, if any.
+fn update_chat_id(raw_update: &Value) -> Option<Integer> {
+    ["message", "edited_message", "channel_post", "edited_channel_post"].iter()
+        .find_map(|kind| raw_update.get(kind))
+        .or_else(|| raw_update.pointer("/callback_query/message"))
+        .and_then(|message| message.pointer("/chat/id"))
+        .and_then(Value::as_i64)
+}
+
+/// String values of `field` are replaced with a placeholder, numbers with zero.
+fn redact(value: &mut Value, field: &str) {
+    match value {
+        Value::Object(object) =>
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(string) if key == field =>
+                        *string = REDACTED.to_string(),
+                    Value::Number(..) if key == field =>
+                        *value = 0.into(),
+                    value =>
+                        redact(value, field),
+                }
+            },
+        Value::Array(array) =>
+            for value in array.iter_mut() {
+                redact(value, field);
+            },
+        _ =>
+            (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use serde_json::{
+        json,
+        Value,
+    };
+
+    use super::{
+        CliArgs,
+        Recorder,
+    };
+
+    fn message(chat_id: i64, text: &str) -> Value {
+        json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 10,
+                "from": { "id": 100, "is_bot": false, "first_name": "someone", "username": "someone", },
+                "chat": { "id": chat_id, "type": "group", "title": "group", },
+                "date": 0,
+                "text": text,
+            },
+        })
+    }
+
+    fn read_lines(path: &std::path::Path) -> Vec<Value> {
+        fs::read_to_string(path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn filter_redact_rotate() {
+        let dir = std::env::temp_dir().join(format!("beercan-recorder-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("updates.jsonl");
+        let cli_args = CliArgs::parse_from([
+            "test",
+            "--record-file", path.to_str().unwrap(),
+            "--record-max-bytes", "400",
+            "--record-max-files", "1",
+            "--record-chat-id", "-10",
+            "--record-redact", "username",
+            "--record-redact", "first_name",
+        ]);
+        let mut recorder = Recorder::open(&cli_args).unwrap().unwrap();
+
+        recorder.record(&message(-10, "first")).unwrap();
+        recorder.record(&message(-20, "other chat")).unwrap();
+        let lines = read_lines(&path);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["update"]["message"]["text"], "first");
+        assert_eq!(lines[0]["update"]["message"]["from"]["username"], "[redacted]");
+        assert_eq!(lines[0]["update"]["message"]["from"]["first_name"], "[redacted]");
+        assert_eq!(lines[0]["update"]["message"]["from"]["id"], 100);
+        assert!(lines[0]["received_at"].is_string());
+
+        recorder.record(&message(-10, "second")).unwrap();
+        recorder.record(&message(-10, "third")).unwrap();
+        assert_eq!(read_lines(&path)[0]["update"]["message"]["text"], "third");
+        assert_eq!(read_lines(&dir.join("updates.jsonl.1"))[0]["update"]["message"]["text"], "second");
+        assert!(!dir.join("updates.jsonl.2").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
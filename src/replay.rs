@@ -0,0 +1,303 @@
+use std::{
+    io,
+    fs,
+    path::{
+        PathBuf,
+    },
+    time::{
+        Duration,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use serde::{
+    Deserialize,
+};
+
+use tokio::time::{
+    Instant,
+};
+
+use telegram_bot::{
+    types::{
+        ChatId,
+        MessageId,
+    },
+};
+
+use crate::{
+    bot_module,
+    clock::{
+        Clock,
+        VirtualClock,
+    },
+    telegram_client::{
+        fake::{
+            FakeClient,
+        },
+        self,
+        Call,
+        TelegramClient,
+        OutgoingMessage,
+    },
+};
+
+pub const DEFAULT_TAIL_S_STR: &str = "600";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// updates recorded with `--record-file`
+    #[clap(name = "UPDATES_FILE")]
+    updates_file: PathBuf,
+
+    /// virtual time to keep the modules running after the last update (in seconds)
+    #[clap(long = "tail-s", default_value = DEFAULT_TAIL_S_STR)]
+    tail_s: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile { path: PathBuf, error: io::Error, },
+    ParseLine { path: PathBuf, line: usize, error: serde_json::Error, },
+}
+
+/// Line of the record file.
+#[derive(Deserialize)]
+struct RecordedLine {
+    received_at: DateTime<Utc>,
+    update: serde_json::Value,
+}
+
+struct RecordedUpdate {
+    received_at: DateTime<Utc>,
+    update: bot_module::IncomingUpdate,
+}
+
+pub struct Recording {
+    updates: Vec<RecordedUpdate>,
+}
+
+impl Recording {
+    pub fn read(cli_args: &CliArgs) -> Result<Recording, Error> {
+        let path = &cli_args.updates_file;
+        let contents = fs::read_to_string(path)
+            .map_err(|error| Error::ReadFile { path: path.clone(), error, })?;
+        let mut updates = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parse_error = |error| Error::ParseLine { path: path.clone(), line: index + 1, error, };
+            let RecordedLine { received_at, update: raw, } = serde_json::from_str(line)
+                .map_err(parse_error)?;
+            let update = serde_json::from_value(raw.clone())
+                .map_err(parse_error)?;
+            updates.push(RecordedUpdate { received_at, update: bot_module::IncomingUpdate::new(update, &raw), });
+        }
+        log::info!("replaying {} updates from {:?}", updates.len(), path);
+        Ok(Recording { updates, })
+    }
+
+    /// Virtual clock starting at the first recorded update.
+    pub fn clock(&self) -> VirtualClock {
+        VirtualClock::new(self.updates.first().map_or_else(Utc::now, |recorded_update| recorded_update.received_at))
+    }
+}
+
+/// Fake client printing every request with its virtual time as a json line.
+pub struct ReplayClient {
+    fake: FakeClient,
+    clock: VirtualClock,
+}
+
+impl ReplayClient {
+    pub fn new(clock: VirtualClock) -> ReplayClient {
+        ReplayClient {
+            fake: FakeClient::new(),
+            clock,
+        }
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.fake.calls()
+    }
+
+    fn print(&self, call: &Call) {
+        let mut action = call.to_json();
+        action["at"] = serde_json::json!(self.clock.now());
+        println!("{}", action);
+    }
+}
+
+#[async_trait::async_trait]
+impl TelegramClient for ReplayClient {
+    async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, telegram_client::Error> {
+        self.print(&Call::Send(message.clone()));
+        self.fake.send_message(message).await
+    }
+
+    async fn forward_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    )
+        -> Result<MessageId, telegram_client::Error>
+    {
+        self.print(&Call::Forward { chat_id, from_chat_id, message_id, disable_notification, });
+        self.fake.forward_message(chat_id, from_chat_id, message_id, disable_notification).await
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), telegram_client::Error> {
+        self.print(&Call::Delete { chat_id, message_id, });
+        self.fake.delete_message(chat_id, message_id).await
+    }
+}
+
+/// Dispatches every update at its recorded time, then lets the modules run
+/// for `--tail-s` more. Time only moves when the modules are idle, so hours of
+/// history take as long as handling the updates takes.
+pub async fn run(recording: Recording, cli_args: &CliArgs, clock: &VirtualClock, registry: &mut bot_module::Registry, context: &bot_module::Context) {
+    for RecordedUpdate { received_at, update, } in recording.updates {
+        run_until(clock.instant_at(received_at), registry, context).await;
+        registry.dispatch(update, context).await;
+    }
+    run_until(Instant::now() + Duration::from_secs(cli_args.tail_s), registry, context).await;
+}
+
+/// Waits for `deadline`, restarting failed modules meanwhile as the bot does.
+async fn run_until(deadline: Instant, registry: &mut bot_module::Registry, context: &bot_module::Context) {
+    loop {
+        let restart_due = registry.restart_due();
+        tokio::select! {
+            () = restart_due =>
+                registry.restart_failed(context).await,
+            () = tokio::time::sleep_until(deadline) =>
+                return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::{
+            Arc,
+        },
+        time::{
+            Duration,
+        },
+    };
+
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use serde_json::{
+        json,
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            MessageId,
+        },
+    };
+
+    use super::{
+        run,
+        CliArgs,
+        Recording,
+        ReplayClient,
+    };
+
+    use crate::{
+        bot_module,
+        health,
+        vaccine_reminder,
+        clock::{
+            Clock,
+            VirtualClock,
+        },
+        state_store::{
+            StateStore,
+        },
+        telegram_client::{
+            Call,
+            TelegramClient,
+        },
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_passed_to_the_fake() {
+        let client = ReplayClient::new(VirtualClock::new(Utc.with_ymd_and_hms(2022, 3, 1, 10, 0, 0).unwrap()));
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        client.delete_message(ChatId::new(-10), MessageId::new(7)).await.unwrap();
+        assert!(matches!(client.calls()[..], [Call::Delete { .. }]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recorded_question_is_answered() {
+        let message = |message_id, user_id, text| json!({
+            "message_id": message_id,
+            "from": { "id": user_id, "is_bot": false, "first_name": "someone", },
+            "chat": { "id": -10, "type": "supergroup", "title": "beercan", },
+            "date": 0,
+            "text": text,
+        });
+        let lines = [
+            json!({ "received_at": "2022-03-01T10:00:00Z", "update": { "update_id": 1, "message": message(7, 200, "а где тут вопрос?"), }, }),
+            json!({ "received_at": "2022-03-01T12:00:00Z", "update": { "update_id": 2, "message": message(8, 100, "кто пойдёт гулять?"), }, }),
+        ];
+        let path = std::env::temp_dir().join(format!("beercan-replay-{}.jsonl", std::process::id()));
+        fs::write(&path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).unwrap();
+        let cli_args = CliArgs::parse_from(["test", path.to_str().unwrap()]);
+        let recording = Recording::read(&cli_args).unwrap();
+        fs::remove_file(&path).ok();
+
+        let clock = recording.clock();
+        let client = Arc::new(ReplayClient::new(clock));
+        let context = bot_module::Context {
+            client: client.clone(),
+            state_store: Arc::new(StateStore::in_memory()),
+            clock: Arc::new(clock),
+        };
+        let health = Arc::new(health::Health::new(&health::CliArgs::parse_from(["test"]), false));
+        let mut registry = bot_module::Registry::new(&bot_module::CliArgs::parse_from(["test"]), health);
+        let config = vaccine_reminder::Config { user_id: 100, group_id: -10, };
+        registry.reload(&[config], vaccine_reminder::VaccineReminder::new, &context).await;
+
+        run(recording, &cli_args, &clock, &mut registry, &context).await;
+        // hours of history and the tail pass on the virtual clock only
+        assert_eq!(clock.now(), Utc.with_ymd_and_hms(2022, 3, 1, 12, 10, 0).unwrap());
+        registry.shutdown().await;
+
+        let calls = client.calls();
+        assert_eq!(calls.len(), 1);
+        match &calls[0] {
+            Call::Send(reply) => {
+                assert_eq!(reply.chat_id, ChatId::new(-10));
+                assert_eq!(reply.maybe_reply_to, Some(MessageId::new(8)));
+            },
+            other_call =>
+                panic!("unexpected call: {:?}", other_call),
+        }
+    }
+}
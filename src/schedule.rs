@@ -0,0 +1,410 @@
+use std::{
+    fmt,
+    convert::{
+        TryFrom,
+    },
+};
+
+use chrono::{
+    offset::{
+        LocalResult,
+    },
+    DateTime,
+    Datelike,
+    Duration,
+    NaiveDate,
+    NaiveTime,
+    NaiveDateTime,
+    Offset,
+    Timelike,
+    TimeZone,
+    Utc,
+};
+
+use serde::{
+    Deserialize,
+};
+
+/// Dates are searched this far ahead for the next run: long enough to reach
+/// the next February 29th.
+const MAX_DAYS_AHEAD: i64 = 8 * 366;
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const WEEKDAY_NAMES: [&str; 7] = ["sunday", "monday", "tuesday", "wednesday", "thursday", "friday", "saturday"];
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    FieldCount { found: usize, },
+    InvalidField { field: &'static str, value: String, },
+    OutOfRange { field: &'static str, value: u32, },
+    InvalidRule(String),
+    NeverFires,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FieldCount { found, } =>
+                write!(f, "cron expression needs 5 fields (minute hour day month weekday), found {}", found),
+            Error::InvalidField { field, value, } =>
+                write!(f, "invalid {} field {:?}", field, value),
+            Error::OutOfRange { field, value, } =>
+                write!(f, "{} {} is out of range", field, value),
+            Error::InvalidRule(rule) =>
+                write!(f, "invalid rule {:?}, expected e.g. \"every weekday at 09:00\"", rule),
+            Error::NeverFires =>
+                write!(f, "schedule never fires"),
+        }
+    }
+}
+
+/// When a job runs: a cron expression (`"0 9 * * 1-5"`) or a rule
+/// (`"every weekday at 09:00"`), matched against the wall time of a zone.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+    source: String,
+    seconds: u64,
+    minutes: u64,
+    hours: u32,
+    /// Bits `1..=31`.
+    days_of_month: u32,
+    /// Bits `1..=12`.
+    months: u16,
+    /// Bits `0..=6`, sunday is `0`.
+    days_of_week: u8,
+    /// Cron semantics: if both days of month and days of week are restricted,
+    /// a day matching either of them fires.
+    days_either: bool,
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Schedule, Error> {
+        let schedule = if source.trim_start().to_lowercase().starts_with("every ") {
+            parse_rule(&source)?
+        } else {
+            parse_cron(&source)?
+        };
+        if schedule.next_after(&Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()).is_none() {
+            return Err(Error::NeverFires);
+        }
+        Ok(schedule)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Schedule {
+    /// First run strictly after `after`, in the time zone of `after`. Clock
+    /// changes are handled as in RFC 5545:
+    /// * a wall time skipped by moving the clocks forward runs as much later
+    ///   as the change is long, e.g. 02:30 runs at 03:30 when 02:00 becomes 03:00;
+    /// * a wall time repeated by moving the clocks back runs once, at its
+    ///   first occurrence.
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let time_zone = after.timezone();
+        // a day earlier: a run moved past a clock change may come from the day before
+        let mut date = after.naive_local().date().checked_sub_signed(Duration::days(1))?;
+        for _ in 0 .. MAX_DAYS_AHEAD {
+            if self.matches_date(date) {
+                let maybe_next = self.times()
+                    .filter_map(|time| resolve(&time_zone, date.and_time(time)))
+                    .filter(|datetime| datetime > after)
+                    .min();
+                if maybe_next.is_some() {
+                    return maybe_next;
+                }
+            }
+            date = date.checked_add_signed(Duration::days(1))?;
+        }
+        None
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !has_bit(self.months as u64, date.month()) {
+            return false;
+        }
+        let day_of_month = has_bit(self.days_of_month as u64, date.day());
+        let day_of_week = has_bit(self.days_of_week as u64, date.weekday().num_days_from_sunday());
+        if self.days_either {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    /// Times of day to fire at, ascending.
+    fn times(&self) -> impl Iterator<Item = NaiveTime> + '_ {
+        (0 .. 24).filter(move |hour| has_bit(self.hours as u64, *hour))
+            .flat_map(move |hour| (0 .. 60).filter(move |minute| has_bit(self.minutes, *minute)).map(move |minute| (hour, minute)))
+            .flat_map(move |(hour, minute)| {
+                (0 .. 60).filter(move |second| has_bit(self.seconds, *second))
+                    .filter_map(move |second| NaiveTime::from_hms_opt(hour, minute, second))
+            })
+    }
+}
+
+/// Instant of the wall time `local` under the clock change rules of `Schedule::next_after`.
+fn resolve<Tz: TimeZone>(time_zone: &Tz, local: NaiveDateTime) -> Option<DateTime<Tz>> {
+    match time_zone.from_local_datetime(&local) {
+        LocalResult::Single(datetime) =>
+            Some(datetime),
+        LocalResult::Ambiguous(earliest, _latest) =>
+            Some(earliest),
+        LocalResult::None => {
+            // in a gap: use the offset in effect before the clock change
+            let before = time_zone.from_local_datetime(&(local - Duration::days(1))).earliest()?;
+            let utc = local - Duration::seconds(before.offset().fix().local_minus_utc() as i64);
+            Some(time_zone.from_utc_datetime(&utc))
+        },
+    }
+}
+
+fn has_bit(bits: u64, index: u32) -> bool {
+    bits & (1 << index) != 0
+}
+
+fn parse_cron(source: &str) -> Result<Schedule, Error> {
+    let fields: Vec<_> = source.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(Error::FieldCount { found: fields.len(), });
+    }
+    let mut days_of_week = parse_field(fields[4], "weekday", 0, 7, &WEEKDAYS)?;
+    // both 0 and 7 are sunday
+    if has_bit(days_of_week, 7) {
+        days_of_week = (days_of_week | 1) & 0x7f;
+    }
+    Ok(Schedule {
+        source: source.to_string(),
+        seconds: 1,
+        minutes: parse_field(fields[0], "minute", 0, 59, &[])?,
+        hours: parse_field(fields[1], "hour", 0, 23, &[])? as u32,
+        days_of_month: parse_field(fields[2], "day", 1, 31, &[])? as u32,
+        months: parse_field(fields[3], "month", 1, 12, &MONTHS)? as u16,
+        days_of_week: days_of_week as u8,
+        days_either: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+    })
+}
+
+/// Cron field: `*`, `N`, `N-M` and `*/S`, `N-M/S` steps, comma separated.
+/// `names` are accepted in place of numbers starting from `min`.
+fn parse_field(field: &str, name: &'static str, min: u32, max: u32, names: &[&str]) -> Result<u64, Error> {
+    let invalid = || Error::InvalidField { field: name, value: field.to_string(), };
+    let value = |string: &str| -> Result<u32, Error> {
+        let value = match names.iter().position(|name| name.eq_ignore_ascii_case(string)) {
+            Some(position) =>
+                min + position as u32,
+            None =>
+                string.parse().map_err(|_| invalid())?,
+        };
+        if value < min || value > max {
+            return Err(Error::OutOfRange { field: name, value, });
+        }
+        Ok(value)
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) =>
+                (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None =>
+                (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" =>
+                (min, max),
+            Some((from, to)) =>
+                (value(from)?, value(to)?),
+            None if step > 1 =>
+                (value(range)?, max),
+            None => {
+                let value = value(range)?;
+                (value, value)
+            },
+        };
+        if from > to {
+            return Err(invalid());
+        }
+        for index in (from ..= to).step_by(step as usize) {
+            bits |= 1 << index;
+        }
+    }
+    Ok(bits)
+}
+
+/// `every <days> at HH:MM[:SS]` where days are `day`, `weekday`, `weekend`
+/// or comma separated day names (`monday,thursday` or `mon,thu`).
+fn parse_rule(source: &str) -> Result<Schedule, Error> {
+    let invalid = || Error::InvalidRule(source.to_string());
+    let rule = source.trim().to_lowercase();
+    let (days, time) = rule.strip_prefix("every ")
+        .and_then(|rest| rest.split_once(" at "))
+        .ok_or_else(invalid)?;
+    let days_of_week = match days.trim() {
+        "day" =>
+            0x7f,
+        "weekday" =>
+            0x3e,
+        "weekend" =>
+            0x41,
+        names => {
+            let mut bits = 0;
+            for name in names.split(',') {
+                let name = name.trim();
+                let position = WEEKDAY_NAMES.iter()
+                    .position(|weekday| name.len() >= 3 && weekday.starts_with(name))
+                    .ok_or_else(invalid)?;
+                bits |= 1 << position;
+            }
+            bits
+        },
+    };
+    let time = NaiveTime::parse_from_str(time.trim(), "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time.trim(), "%H:%M"))
+        .map_err(|_| invalid())?;
+    Ok(Schedule {
+        source: source.to_string(),
+        seconds: 1 << time.second(),
+        minutes: 1 << time.minute(),
+        hours: 1 << time.hour(),
+        days_of_month: u32::MAX << 1,
+        months: u16::MAX << 1,
+        days_of_week,
+        days_either: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::{
+            TryFrom,
+        },
+    };
+
+    use chrono::{
+        Datelike,
+        Timelike,
+        TimeZone,
+        Utc,
+    };
+
+    use chrono_tz::{
+        Europe::{
+            Berlin,
+        },
+    };
+
+    use super::{
+        Error,
+        Schedule,
+    };
+
+    fn schedule(source: &str) -> Schedule {
+        Schedule::try_from(source.to_string()).unwrap()
+    }
+
+    fn next(schedule: &Schedule, after: (i32, u32, u32, u32, u32, u32)) -> (i32, u32, u32, u32, u32, u32) {
+        let after = Utc.with_ymd_and_hms(after.0, after.1, after.2, after.3, after.4, after.5).unwrap();
+        let next = schedule.next_after(&after).unwrap().naive_utc();
+        (next.year(), next.month(), next.day(), next.hour(), next.minute(), next.second())
+    }
+
+    #[test]
+    fn cron_expressions() {
+        // 2022-03-09 is a wednesday
+        let weekdays_at_nine = schedule("0 9 * * 1-5");
+        assert_eq!(next(&weekdays_at_nine, (2022, 3, 9, 8, 0, 0)), (2022, 3, 9, 9, 0, 0));
+        assert_eq!(next(&weekdays_at_nine, (2022, 3, 9, 9, 0, 0)), (2022, 3, 10, 9, 0, 0));
+        assert_eq!(next(&weekdays_at_nine, (2022, 3, 11, 10, 0, 0)), (2022, 3, 14, 9, 0, 0));
+
+        let every_quarter = schedule("*/15 * * * *");
+        assert_eq!(next(&every_quarter, (2022, 3, 9, 23, 50, 0)), (2022, 3, 10, 0, 0, 0));
+
+        // day of month or sunday
+        let either = schedule("30 12 1,15 * sun");
+        assert_eq!(next(&either, (2022, 3, 9, 0, 0, 0)), (2022, 3, 13, 12, 30, 0));
+        assert_eq!(next(&either, (2022, 3, 13, 13, 0, 0)), (2022, 3, 15, 12, 30, 0));
+
+        let leap_day = schedule("0 0 29 feb *");
+        assert_eq!(next(&leap_day, (2022, 3, 1, 0, 0, 0)), (2024, 2, 29, 0, 0, 0));
+        assert_eq!(next(&schedule("0 0 * * 7"), (2022, 3, 9, 0, 0, 0)), (2022, 3, 13, 0, 0, 0));
+    }
+
+    #[test]
+    fn rules() {
+        let weekdays = schedule("every weekday at 09:00");
+        assert_eq!(next(&weekdays, (2022, 3, 11, 10, 0, 0)), (2022, 3, 14, 9, 0, 0));
+        let weekend = schedule("Every weekend at 10:30");
+        assert_eq!(next(&weekend, (2022, 3, 9, 0, 0, 0)), (2022, 3, 12, 10, 30, 0));
+        let daily = schedule("every day at 17:00:30");
+        assert_eq!(next(&daily, (2022, 3, 9, 17, 0, 30)), (2022, 3, 10, 17, 0, 30));
+        let named = schedule("every monday, thu at 08:00");
+        assert_eq!(next(&named, (2022, 3, 9, 0, 0, 0)), (2022, 3, 10, 8, 0, 0));
+        assert_eq!(next(&named, (2022, 3, 10, 8, 0, 0)), (2022, 3, 14, 8, 0, 0));
+    }
+
+    // Europe/Berlin: 2022-03-27 02:00 +01:00 becomes 03:00 +02:00,
+    // 2022-10-30 03:00 +02:00 becomes 02:00 +01:00
+
+    #[test]
+    fn spring_forward() {
+        let at_half_past_two = schedule("every day at 02:30");
+        let after = Berlin.with_ymd_and_hms(2022, 3, 26, 12, 0, 0).unwrap();
+        let skipped = at_half_past_two.next_after(&after).unwrap();
+        assert_eq!(skipped.naive_utc(), Utc.with_ymd_and_hms(2022, 3, 27, 1, 30, 0).unwrap().naive_utc());
+        assert_eq!(skipped.to_rfc3339(), "2022-03-27T03:30:00+02:00");
+        let next_day = at_half_past_two.next_after(&skipped).unwrap();
+        assert_eq!(next_day.to_rfc3339(), "2022-03-28T02:30:00+02:00");
+
+        // the run moved past the change does not hold up the ones after it
+        let quarters = schedule("*/15 1-3 * * *");
+        let after = Berlin.with_ymd_and_hms(2022, 3, 27, 1, 50, 0).unwrap();
+        let runs: Vec<_> = std::iter::successors(quarters.next_after(&after), |run| quarters.next_after(run))
+            .take(6)
+            .map(|run| run.to_rfc3339())
+            .collect();
+        assert_eq!(runs, [
+            "2022-03-27T03:00:00+02:00",
+            "2022-03-27T03:15:00+02:00",
+            "2022-03-27T03:30:00+02:00",
+            "2022-03-27T03:45:00+02:00",
+            "2022-03-28T01:00:00+02:00",
+            "2022-03-28T01:15:00+02:00",
+        ]);
+    }
+
+    #[test]
+    fn fall_back() {
+        let at_half_past_two = schedule("every day at 02:30");
+        let after = Berlin.with_ymd_and_hms(2022, 10, 29, 12, 0, 0).unwrap();
+        let first = at_half_past_two.next_after(&after).unwrap();
+        assert_eq!(first.to_rfc3339(), "2022-10-30T02:30:00+02:00");
+        let next_day = at_half_past_two.next_after(&first).unwrap();
+        assert_eq!(next_day.to_rfc3339(), "2022-10-31T02:30:00+01:00");
+
+        // the repeated hour does not fire again
+        let hourly = schedule("0 * * * *");
+        let after = Berlin.from_utc_datetime(&Utc.with_ymd_and_hms(2022, 10, 30, 0, 0, 0).unwrap().naive_utc());
+        assert_eq!(after.to_rfc3339(), "2022-10-30T02:00:00+02:00");
+        assert_eq!(hourly.next_after(&after).unwrap().to_rfc3339(), "2022-10-30T03:00:00+01:00");
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        let error = |source: &str| Schedule::try_from(source.to_string()).unwrap_err();
+        assert_eq!(error("0 9 * *"), Error::FieldCount { found: 4, });
+        assert_eq!(error("60 9 * * *"), Error::OutOfRange { field: "minute", value: 60, });
+        assert_eq!(error("0 9 * * 5-1"), Error::InvalidField { field: "weekday", value: "5-1".to_string(), });
+        assert_eq!(error("0 0 30 2 *"), Error::NeverFires);
+        assert!(matches!(error("every fortnight at 09:00"), Error::InvalidRule(..)));
+        assert!(matches!(error("every day at 25:00"), Error::InvalidRule(..)));
+    }
+}
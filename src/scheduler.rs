@@ -0,0 +1,590 @@
+use std::{
+    sync::{
+        Arc,
+    },
+    time::{
+        Duration,
+    },
+    collections::{
+        HashSet,
+    },
+};
+
+use chrono::{
+    offset::{
+        Utc,
+    },
+    DateTime,
+};
+
+use chrono_tz::{
+    Tz,
+};
+
+use serde::{
+    Serialize,
+    Deserialize,
+};
+
+use telegram_bot::{
+    types::{
+        ChatId,
+        Integer,
+    },
+    ParseMode,
+};
+
+use futures::{
+    FutureExt,
+};
+
+use crate::{
+    metrics,
+    bot_module,
+    clock::{
+        Clock,
+    },
+    schedule::{
+        Schedule,
+    },
+    telegram_client::{
+        self,
+        TelegramClient,
+        OutgoingMessage,
+    },
+    state_store::{
+        self,
+        Versioned,
+        StateStore,
+    },
+};
+
+pub const DEFAULT_GRACE_S: u64 = 300;
+/// A year keeps even a yearly job within grace, and the date arithmetic far from overflow.
+pub const MAX_GRACE_S: u64 = 366 * 24 * 60 * 60;
+
+/// Long sleeps are split into steps of this length to notice wall clock jumps:
+/// a monotonic sleep does not follow a host suspend or an NTP step.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Scheduled message job, every `[[scheduler]]` table is a separate job.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// unique job name, used in logs, metrics and the state store
+    pub name: String,
+    /// disabled jobs are not run
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// cron expression, e.g. "0 9 * * 1-5", or rule, e.g. "every weekday at 09:00"
+    pub schedule: Schedule,
+    /// IANA time zone the schedule is in, e.g. "Europe/Moscow"
+    pub time_zone: Tz,
+    /// group or supergroup id to post to
+    pub group_id: Integer,
+    /// forum topic to post in (`message_thread_id`), general topic if omitted
+    #[serde(default)]
+    pub topic_id: Option<Integer>,
+    /// message text, `{date}`, `{time}` and `{weekday}` are replaced with the planned run time
+    pub message: String,
+    /// message formatting, plain text if omitted
+    #[serde(default)]
+    pub parse_mode: Option<Format>,
+    /// a run found late by up to this much (bot restart, host suspend, wall clock step) is still sent,
+    /// later ones are skipped (in seconds)
+    #[serde(default = "default_grace_s")]
+    pub grace_s: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_grace_s() -> u64 {
+    DEFAULT_GRACE_S
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Markdown,
+    MarkdownV2,
+    Html,
+}
+
+impl From<Format> for ParseMode {
+    fn from(format: Format) -> ParseMode {
+        match format {
+            Format::Markdown =>
+                ParseMode::Markdown,
+            Format::MarkdownV2 =>
+                ParseMode::MarkdownV2,
+            Format::Html =>
+                ParseMode::Html,
+        }
+    }
+}
+
+/// Checks the `[[scheduler]]` jobs as a whole: names identify the jobs.
+pub fn validate(configs: &[Config]) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for config in configs {
+        if !names.insert(&config.name) {
+            return Err(Error::DuplicateJobName(config.name.clone()));
+        }
+        if config.grace_s > MAX_GRACE_S {
+            return Err(Error::GraceTooLong { name: config.name.clone(), grace_s: config.grace_s, });
+        }
+    }
+    Ok(())
+}
+
+/// Jobs to run: the enabled ones.
+pub fn enabled(configs: &[Config]) -> Vec<Config> {
+    configs.iter()
+        .filter(|config| config.enabled)
+        .cloned()
+        .collect()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    DuplicateJobName(String),
+    GraceTooLong { name: String, grace_s: u64, },
+    NoNextRun { schedule: String, },
+    TelegramApiSend(telegram_client::Error),
+    StateStore(state_store::Error),
+}
+
+/// Job history as saved in the state store.
+#[derive(Default, Serialize, Deserialize)]
+struct JobHistory {
+    /// Planned time of the last run, saved before its message is sent.
+    maybe_last_run: Option<DateTime<Utc>>,
+}
+
+impl Versioned for JobHistory {
+    const VERSION: u32 = 1;
+}
+
+#[derive(Clone)]
+struct Job {
+    name: String,
+    state_key: String,
+    schedule: Schedule,
+    time_zone: Tz,
+    chat_id: ChatId,
+    maybe_topic_id: Option<Integer>,
+    message: String,
+    maybe_parse_mode: Option<ParseMode>,
+    grace: chrono::Duration,
+}
+
+pub struct Scheduler {
+    job: Job,
+}
+
+impl Scheduler {
+    /// Jobs are identified by name rather than position: disabling one leaves
+    /// the others running.
+    pub fn new(config: &Config) -> Scheduler {
+        Scheduler {
+            job: Job {
+                name: format!("scheduler#{}", config.name),
+                state_key: format!("scheduler/{}/history", config.name),
+                schedule: config.schedule.clone(),
+                time_zone: config.time_zone,
+                chat_id: config.group_id.into(),
+                maybe_topic_id: config.topic_id,
+                message: config.message.clone(),
+                maybe_parse_mode: config.parse_mode.map(ParseMode::from),
+                grace: chrono::Duration::seconds(config.grace_s as i64),
+            },
+        }
+    }
+}
+
+impl From<Error> for bot_module::Error {
+    fn from(error: Error) -> bot_module::Error {
+        bot_module::Error::new(error)
+    }
+}
+
+#[async_trait::async_trait]
+impl bot_module::BotModule for Scheduler {
+    fn name(&self) -> &str {
+        &self.job.name
+    }
+
+    async fn handle_update(&mut self, _update: &bot_module::IncomingUpdate, _context: &bot_module::Context) -> Result<(), bot_module::Error> {
+        Ok(())
+    }
+
+    fn background_tasks(&mut self, context: &bot_module::Context) -> Vec<bot_module::BackgroundTask> {
+        let job = self.job.clone();
+        let client = context.client.clone();
+        let clock = context.clock.clone();
+        let state_store = context.state_store.clone();
+        vec![
+            bot_module::BackgroundTask {
+                name: "job".to_string(),
+                run: Box::new(move || {
+                    run_job(job.clone(), client.clone(), clock.clone(), state_store.clone())
+                        .map(|result| result.map_err(bot_module::Error::from))
+                        .boxed()
+                }),
+            },
+        ]
+    }
+
+    fn migrate_chat(&mut self, from: ChatId, to: ChatId) -> bool {
+        if self.job.chat_id == from {
+            self.job.chat_id = to;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Sends the job message at every planned run, the supervisor restarts the
+/// loop on error.
+async fn run_job(job: Job, client: Arc<dyn TelegramClient>, clock: Arc<dyn Clock>, state_store: Arc<StateStore>) -> Result<(), Error> {
+    log::debug!("starting {} on {:?} ({}) in {:?}", job.name, job.schedule.to_string(), job.time_zone, job.chat_id);
+    let mut history: JobHistory = state_store.load(&job.state_key)
+        .map_err(Error::StateStore)?
+        .unwrap_or_default();
+    // runs missed while the bot was down are caught up if still within the
+    // grace window, a new job starts with the next run
+    let mut after = match history.maybe_last_run {
+        Some(last_run) => {
+            log::debug!("{}: resuming after the run planned at {}", job.name, last_run.with_timezone(&job.time_zone));
+            last_run.with_timezone(&job.time_zone)
+        },
+        None =>
+            clock.now().with_timezone(&job.time_zone),
+    };
+    loop {
+        let planned = job.schedule.next_after(&after)
+            .ok_or_else(|| Error::NoNextRun { schedule: job.schedule.to_string(), })?;
+        sleep_until(clock.as_ref(), planned.with_timezone(&Utc)).await;
+        let fired_at = clock.now();
+        if fired_at - planned.with_timezone(&Utc) > job.grace {
+            log::warn!(
+                "{}: run planned at {} is found at {}, over the {}s grace window: skipping the runs missed",
+                job.name, planned, fired_at.with_timezone(&job.time_zone), job.grace.num_seconds(),
+            );
+            metrics::SCHEDULER_SKIPPED.with_label_values(&[&job.name]).inc();
+            // runs still within the grace window go next
+            after = (fired_at - job.grace).with_timezone(&job.time_zone);
+            continue;
+        }
+        report_fired(&job.name, planned, fired_at);
+
+        // the run is saved before sending: a crash in between loses it rather
+        // than sending it twice after the restart
+        let maybe_previous_run = history.maybe_last_run.replace(planned.with_timezone(&Utc));
+        state_store.save(&job.state_key, &history)
+            .map_err(Error::StateStore)?;
+
+        let mut message = OutgoingMessage::new(job.chat_id, render(&job.message, planned));
+        message.maybe_parse_mode = job.maybe_parse_mode;
+        message.maybe_message_thread_id = job.maybe_topic_id;
+        if let Err(error) = client.send_message(message).await {
+            // not sent: the restarted loop retries the run within the grace window
+            history.maybe_last_run = maybe_previous_run;
+            state_store.save(&job.state_key, &history)
+                .map_err(Error::StateStore)?;
+            return Err(Error::TelegramApiSend(error));
+        }
+        // planned, not the current time: a wall clock stepped back does not repeat the run
+        after = planned;
+    }
+}
+
+/// Sleeps until the clock shows `datetime`, re-checking the wall time every `RECHECK_INTERVAL`.
+async fn sleep_until(clock: &dyn Clock, datetime: DateTime<Utc>) {
+    loop {
+        let left = match (datetime - clock.now()).to_std() {
+            Ok(left) if left > Duration::ZERO =>
+                left,
+            _ =>
+                return,
+        };
+        clock.sleep(left.min(RECHECK_INTERVAL)).await;
+    }
+}
+
+fn render(template: &str, planned: DateTime<Tz>) -> String {
+    template
+        .replace("{date}", &planned.format("%Y-%m-%d").to_string())
+        .replace("{time}", &planned.format("%H:%M").to_string())
+        .replace("{weekday}", &planned.format("%A").to_string())
+}
+
+fn report_fired(name: &str, planned: DateTime<Tz>, fired: DateTime<Utc>) {
+    let planned = planned.with_timezone(&Utc);
+    let to_seconds = |datetime: DateTime<Utc>| datetime.timestamp_millis() as f64 / 1000.0;
+    metrics::SCHEDULER_PLANNED.with_label_values(&[name]).set(to_seconds(planned));
+    metrics::SCHEDULER_FIRED.with_label_values(&[name]).set(to_seconds(fired));
+    metrics::SCHEDULER_DELAY.with_label_values(&[name]).observe(to_seconds(fired) - to_seconds(planned));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::{
+            Duration,
+        },
+        convert::{
+            TryFrom,
+        },
+    };
+
+    use chrono::{
+        offset::{
+            Utc,
+        },
+        DateTime,
+        TimeZone,
+    };
+
+    use chrono_tz::{
+        Europe::{
+            Berlin,
+        },
+    };
+
+    use futures::{
+        future::{
+            BoxFuture,
+        },
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+        },
+    };
+
+    use super::{
+        run_job,
+        render,
+        Job,
+        JobHistory,
+    };
+
+    use crate::{
+        clock::{
+            Clock,
+            VirtualClock,
+        },
+        schedule::{
+            Schedule,
+        },
+        state_store::{
+            StateStore,
+        },
+        telegram_client::{
+            fake::{
+                Call,
+                Method,
+                FakeClient,
+            },
+        },
+    };
+
+    fn job(schedule: &str) -> Job {
+        Job {
+            name: "scheduler#test".to_string(),
+            state_key: "scheduler/test/history".to_string(),
+            schedule: Schedule::try_from(schedule.to_string()).unwrap(),
+            time_zone: Berlin,
+            chat_id: ChatId::new(-1),
+            maybe_topic_id: None,
+            message: "{weekday} {date} {time}".to_string(),
+            maybe_parse_mode: None,
+            grace: chrono::Duration::minutes(5),
+        }
+    }
+
+    /// Virtual clock whose wall time can jump ahead of the timers, as it does
+    /// on a host suspend.
+    struct SuspendedClock {
+        clock: VirtualClock,
+        jump: Mutex<chrono::Duration>,
+    }
+
+    impl SuspendedClock {
+        fn new(started_at: DateTime<Utc>) -> SuspendedClock {
+            SuspendedClock {
+                clock: VirtualClock::new(started_at),
+                jump: Mutex::new(chrono::Duration::zero()),
+            }
+        }
+
+        fn suspend(&self, duration: chrono::Duration) {
+            *self.jump.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for SuspendedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.clock.now() + *self.jump.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.clock.sleep(duration)
+        }
+    }
+
+    fn texts(client: &FakeClient) -> Vec<String> {
+        client.calls().into_iter()
+            .filter_map(|call| match call {
+                Call::Send(message) =>
+                    Some(message.text),
+                _ =>
+                    None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let planned = Berlin.with_ymd_and_hms(2022, 3, 9, 17, 0, 0).unwrap();
+        assert_eq!(render("{weekday} {date} {time}!", planned), "Wednesday 2022-03-09 17:00!");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn weekday_job_runs_on_weekdays_only() {
+        // friday
+        let started_at = Berlin.with_ymd_and_hms(2022, 3, 11, 8, 0, 0).unwrap().with_timezone(&Utc);
+        let client = Arc::new(FakeClient::new());
+        let state_store = Arc::new(StateStore::in_memory());
+        let job_task = tokio::spawn(run_job(job("every weekday at 09:00"), client.clone(), Arc::new(VirtualClock::new(started_at)), state_store.clone()));
+
+        // checked half a second after every planned run
+        tokio::time::sleep(Duration::from_millis(3_600_500)).await;
+        assert_eq!(texts(&client), vec!["Friday 2022-03-11 09:00"]);
+        tokio::time::sleep(Duration::from_secs(3 * 24 * 3600)).await;
+        assert_eq!(texts(&client), vec!["Friday 2022-03-11 09:00", "Monday 2022-03-14 09:00"]);
+        job_task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn daily_job_runs_on_spring_forward_day() {
+        let started_at = Berlin.with_ymd_and_hms(2022, 3, 26, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let client = Arc::new(FakeClient::new());
+        let job_task = tokio::spawn(run_job(
+            job("every day at 02:30"),
+            client.clone(),
+            Arc::new(VirtualClock::new(started_at)),
+            Arc::new(StateStore::in_memory()),
+        ));
+        // 02:30 does not exist on sunday: it runs at 03:30, which is 14.5 hours later
+        tokio::time::sleep(Duration::from_millis(14 * 3600 * 1000 + 1_800_500)).await;
+        assert_eq!(texts(&client), vec!["Sunday 2022-03-27 03:30"]);
+        tokio::time::sleep(Duration::from_secs(23 * 3600)).await;
+        assert_eq!(texts(&client), vec!["Sunday 2022-03-27 03:30", "Monday 2022-03-28 02:30"]);
+        job_task.abort();
+    }
+
+    fn state_store_with_last_run(last_run: (i32, u32, u32, u32, u32, u32)) -> Arc<StateStore> {
+        let state_store = Arc::new(StateStore::in_memory());
+        let (year, month, day, hour, minute, second) = last_run;
+        let history = JobHistory {
+            maybe_last_run: Some(Berlin.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap().with_timezone(&Utc)),
+        };
+        state_store.save("scheduler/test/history", &history).unwrap();
+        state_store
+    }
+
+    /// Runs the job on a clock started at `started_at` for `duration`, as a bot run between restarts.
+    async fn run_for(client: &Arc<FakeClient>, state_store: &Arc<StateStore>, started_at: (i32, u32, u32, u32, u32, u32), duration: Duration) {
+        let (year, month, day, hour, minute, second) = started_at;
+        let started_at = Berlin.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap().with_timezone(&Utc);
+        let job_task = tokio::spawn(run_job(job("every day at 17:00"), client.clone(), Arc::new(VirtualClock::new(started_at)), state_store.clone()));
+        tokio::time::sleep(duration).await;
+        job_task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sent_run_is_not_repeated_after_restart() {
+        let client = Arc::new(FakeClient::new());
+        let state_store = Arc::new(StateStore::in_memory());
+        run_for(&client, &state_store, (2022, 3, 9, 16, 59, 59), Duration::from_millis(1_500)).await;
+        assert_eq!(texts(&client).len(), 1);
+
+        // crash loop around the planned run, with the clock a bit behind or ahead
+        run_for(&client, &state_store, (2022, 3, 9, 16, 59, 59), Duration::from_secs(3600)).await;
+        run_for(&client, &state_store, (2022, 3, 9, 17, 0, 1), Duration::from_secs(3600)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_missed_by_restart_is_caught_up() {
+        let client = Arc::new(FakeClient::new());
+        let state_store = state_store_with_last_run((2022, 3, 8, 17, 0, 0));
+        run_for(&client, &state_store, (2022, 3, 9, 17, 0, 5), Duration::from_secs(1)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_run_is_skipped_after_restart() {
+        let client = Arc::new(FakeClient::new());
+        let state_store = state_store_with_last_run((2022, 3, 8, 17, 0, 0));
+        run_for(&client, &state_store, (2022, 3, 9, 18, 0, 0), Duration::from_secs(3600)).await;
+        assert!(texts(&client).is_empty());
+        run_for(&client, &state_store, (2022, 3, 10, 16, 0, 0), Duration::from_secs(3601)).await;
+        assert_eq!(texts(&client), vec!["Thursday 2022-03-10 17:00"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_run_is_retried_after_restart() {
+        let client = Arc::new(FakeClient::new());
+        let state_store = state_store_with_last_run((2022, 3, 8, 17, 0, 0));
+        client.fail_next(Method::Send, "Too Many Requests");
+        run_for(&client, &state_store, (2022, 3, 9, 16, 59, 0), Duration::from_secs(61)).await;
+        // the fake records the failed attempt as well
+        assert_eq!(texts(&client).len(), 1);
+        // restarted by the supervisor
+        run_for(&client, &state_store, (2022, 3, 9, 17, 0, 30), Duration::from_secs(1)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00", "Wednesday 2022-03-09 17:00"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_missed_during_suspend_fires_within_grace() {
+        let started_at = Berlin.with_ymd_and_hms(2022, 3, 9, 16, 0, 0).unwrap().with_timezone(&Utc);
+        let client = Arc::new(FakeClient::new());
+        let clock = Arc::new(SuspendedClock::new(started_at));
+        let job_task = tokio::spawn(run_job(job("every day at 17:00"), client.clone(), clock.clone(), Arc::new(StateStore::in_memory())));
+
+        tokio::time::sleep(Duration::from_secs(30 * 60)).await;
+        // wakes up at 17:02
+        clock.suspend(chrono::Duration::minutes(32));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00"]);
+        job_task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_runs_are_skipped() {
+        let started_at = Berlin.with_ymd_and_hms(2022, 3, 9, 15, 50, 0).unwrap().with_timezone(&Utc);
+        let client = Arc::new(FakeClient::new());
+        let clock = Arc::new(SuspendedClock::new(started_at));
+        let job_task = tokio::spawn(run_job(job("0 * * * *"), client.clone(), clock.clone(), Arc::new(StateStore::in_memory())));
+
+        tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+        // wakes up at 17:02: 16:00 is too late, 17:00 is within the grace window
+        clock.suspend(chrono::Duration::minutes(67));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00"]);
+        tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        assert_eq!(texts(&client), vec!["Wednesday 2022-03-09 17:00", "Wednesday 2022-03-09 18:00"]);
+        job_task.abort();
+    }
+}
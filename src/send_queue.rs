@@ -0,0 +1,533 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+    },
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use tokio::{
+    sync::{
+        oneshot,
+        Notify,
+    },
+    time::{
+        Instant,
+    },
+};
+
+use telegram_bot::{
+    types::{
+        ChatId,
+        MessageId,
+    },
+};
+
+use crate::{
+    metrics,
+    telegram_client::{
+        Error,
+        TelegramClient,
+        OutgoingMessage,
+    },
+};
+
+pub const DEFAULT_SEND_GLOBAL_RATE_PER_S_STR: &str = "30";
+pub const DEFAULT_SEND_CHAT_INTERVAL_MS_STR: &str = "3000";
+pub const DEFAULT_SEND_MAX_ATTEMPTS_STR: &str = "5";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// maximum telegram requests per second over all chats
+    #[clap(long = "send-global-rate-per-s", default_value = DEFAULT_SEND_GLOBAL_RATE_PER_S_STR)]
+    send_global_rate_per_s: u32,
+
+    /// minimum interval between messages sent or forwarded to the same chat (in milliseconds)
+    #[clap(long = "send-chat-interval-ms", default_value = DEFAULT_SEND_CHAT_INTERVAL_MS_STR)]
+    send_chat_interval_ms: u64,
+
+    /// attempts for a request which keeps getting `429 Too Many Requests`
+    #[clap(long = "send-max-attempts", default_value = DEFAULT_SEND_MAX_ATTEMPTS_STR)]
+    send_max_attempts: usize,
+}
+
+impl CliArgs {
+    pub fn chat_interval(&self) -> Duration {
+        Duration::from_millis(self.send_chat_interval_ms)
+    }
+}
+
+/// User visible replies go before background jobs like delete probes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low,
+}
+
+/// Number of requests waiting in the queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub high: usize,
+    pub low: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Operation {
+    Send(OutgoingMessage),
+    Forward {
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    },
+    Delete {
+        chat_id: ChatId,
+        message_id: MessageId,
+    },
+}
+
+impl Operation {
+    /// `sendMessage` is what users see, the other calls come from delete probes.
+    fn priority(&self) -> Priority {
+        match self {
+            Operation::Send(..) =>
+                Priority::High,
+            Operation::Forward { .. } | Operation::Delete { .. } =>
+                Priority::Low,
+        }
+    }
+
+    /// Bot API method name for metrics.
+    fn method(&self) -> &'static str {
+        match self {
+            Operation::Send(..) =>
+                "sendMessage",
+            Operation::Forward { .. } =>
+                "forwardMessage",
+            Operation::Delete { .. } =>
+                "deleteMessage",
+        }
+    }
+
+    /// Whether the request posts a message and so is held by the chat
+    /// interval. Deleting does not post anything: the copies a delete probe
+    /// leaves in the monitor chat are removed without waiting.
+    fn is_chat_limited(&self) -> bool {
+        match self {
+            Operation::Send(..) | Operation::Forward { .. } =>
+                true,
+            Operation::Delete { .. } =>
+                false,
+        }
+    }
+
+    /// Chat the request is rate limited in.
+    fn chat_id(&self) -> ChatId {
+        match self {
+            Operation::Send(message) =>
+                message.chat_id,
+            Operation::Forward { chat_id, .. } | Operation::Delete { chat_id, .. } =>
+                *chat_id,
+        }
+    }
+
+    async fn perform(&self, client: &dyn TelegramClient) -> Result<Option<MessageId>, Error> {
+        match self {
+            Operation::Send(message) =>
+                client.send_message(message.clone()).await.map(Some),
+            Operation::Forward { chat_id, from_chat_id, message_id, disable_notification, } =>
+                client.forward_message(*chat_id, *from_chat_id, *message_id, *disable_notification).await.map(Some),
+            Operation::Delete { chat_id, message_id, } =>
+                client.delete_message(*chat_id, *message_id).await.map(|()| None),
+        }
+    }
+}
+
+struct Job {
+    operation: Operation,
+    attempt: usize,
+    result_tx: oneshot::Sender<Result<Option<MessageId>, Error>>,
+}
+
+#[derive(Default)]
+struct ChatState {
+    busy: bool,
+    /// Chat interval: the next message may be posted at.
+    maybe_next_at: Option<Instant>,
+    /// Flood control: no request to the chat before.
+    maybe_retry_at: Option<Instant>,
+}
+
+impl ChatState {
+    fn ready_at(&self, operation: &Operation) -> Option<Instant> {
+        let maybe_next_at = if operation.is_chat_limited() { self.maybe_next_at } else { None };
+        maybe_next_at.max(self.maybe_retry_at)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    high: VecDeque<Job>,
+    low: VecDeque<Job>,
+    chats: HashMap<ChatId, ChatState>,
+    maybe_global_next_at: Option<Instant>,
+    closed: bool,
+}
+
+struct Shared {
+    client: Arc<dyn TelegramClient>,
+    global_interval: Duration,
+    chat_interval: Duration,
+    max_attempts: usize,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Outbound queue in front of a `TelegramClient`: every request goes through
+/// it, so the per chat and global limits hold for all the modules together.
+/// Requests to the same chat are performed one by one in their order.
+pub struct SendQueue {
+    shared: Arc<Shared>,
+}
+
+impl SendQueue {
+    /// Spawns the scheduler task, it stops when the queue is dropped.
+    pub fn new(client: Arc<dyn TelegramClient>, cli_args: &CliArgs) -> SendQueue {
+        let shared = Arc::new(Shared {
+            client,
+            global_interval: Duration::from_secs(1) / cli_args.send_global_rate_per_s.max(1),
+            chat_interval: cli_args.chat_interval(),
+            max_attempts: cli_args.send_max_attempts.max(1),
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+        });
+        tokio::spawn(run_scheduler(shared.clone()));
+        SendQueue { shared, }
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        let state = self.shared.state.lock().unwrap();
+        QueueDepth {
+            high: state.high.len(),
+            low: state.low.len(),
+        }
+    }
+
+    async fn enqueue(&self, operation: Operation) -> Result<Option<MessageId>, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let priority = operation.priority();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let job = Job { operation, attempt: 1, result_tx, };
+            match priority {
+                Priority::High =>
+                    state.high.push_back(job),
+                Priority::Low =>
+                    state.low.push_back(job),
+            }
+            report_depth(&state);
+        }
+        self.shared.notify.notify_one();
+        log::debug!("send queue depth: {:?}", self.depth());
+        result_rx.await
+            .map_err(|_recv_error| Error::SendQueueTerminated)?
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+#[async_trait::async_trait]
+impl TelegramClient for SendQueue {
+    async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, Error> {
+        let maybe_message_id = self.enqueue(Operation::Send(message)).await?;
+        Ok(maybe_message_id.expect("send returns message id"))
+    }
+
+    async fn forward_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    )
+        -> Result<MessageId, Error>
+    {
+        let operation = Operation::Forward { chat_id, from_chat_id, message_id, disable_notification, };
+        let maybe_message_id = self.enqueue(operation).await?;
+        Ok(maybe_message_id.expect("forward returns message id"))
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), Error> {
+        self.enqueue(Operation::Delete { chat_id, message_id, }).await?;
+        Ok(())
+    }
+}
+
+enum Next {
+    Run(Job),
+    WaitUntil(Instant),
+    Wait,
+    Terminate,
+}
+
+async fn run_scheduler(shared: Arc<Shared>) {
+    loop {
+        let notified = shared.notify.notified();
+        match pick_next(&shared, Instant::now()) {
+            Next::Run(job) => {
+                tokio::spawn(perform(shared.clone(), job));
+            },
+            Next::WaitUntil(wake_at) => {
+                tokio::select! {
+                    () = notified => (),
+                    () = tokio::time::sleep_until(wake_at) => (),
+                }
+            },
+            Next::Wait =>
+                notified.await,
+            Next::Terminate =>
+                break,
+        }
+    }
+    log::debug!("send queue is dropped: scheduler terminated");
+}
+
+/// Takes the first job which may be performed now: high priority first, the
+/// oldest first, skipping chats which are busy or rate limited. A job never
+/// overtakes a waiting one of the same chat.
+fn pick_next(shared: &Shared, now: Instant) -> Next {
+    let mut state = shared.state.lock().unwrap();
+    let State { high, low, chats, maybe_global_next_at, closed, } = &mut *state;
+    if high.is_empty() && low.is_empty() {
+        return if *closed { Next::Terminate } else { Next::Wait };
+    }
+    if let Some(global_next_at) = *maybe_global_next_at {
+        if global_next_at > now {
+            return Next::WaitUntil(global_next_at);
+        }
+    }
+
+    let mut maybe_wake_at: Option<Instant> = None;
+    let mut waiting_chats = HashSet::new();
+    let mut found = None;
+    'queues: for (queue_index, queue) in [&*high, &*low].iter().enumerate() {
+        for (index, job) in queue.iter().enumerate() {
+            let chat_id = job.operation.chat_id();
+            let chat = chats.entry(chat_id).or_default();
+            if chat.busy || waiting_chats.contains(&chat_id) {
+                continue;
+            }
+            match chat.ready_at(&job.operation) {
+                Some(ready_at) if ready_at > now => {
+                    maybe_wake_at = Some(maybe_wake_at.map_or(ready_at, |wake_at| wake_at.min(ready_at)));
+                    waiting_chats.insert(chat_id);
+                    continue;
+                },
+                _ => {
+                    found = Some((queue_index, index));
+                    break 'queues;
+                },
+            }
+        }
+    }
+
+    match found {
+        Some((queue_index, index)) => {
+            let queue = if queue_index == 0 { high } else { low };
+            let job = queue.remove(index).unwrap();
+            metrics::SEND_QUEUE_DEPTH.with_label_values(&[if queue_index == 0 { "high" } else { "low" }]).set(queue.len() as i64);
+            let chat = chats.entry(job.operation.chat_id()).or_default();
+            chat.busy = true;
+            if job.operation.is_chat_limited() {
+                chat.maybe_next_at = Some(now + shared.chat_interval);
+            }
+            *maybe_global_next_at = Some(now + shared.global_interval);
+            Next::Run(job)
+        },
+        None => {
+            // forget chats with nothing to wait for
+            chats.retain(|_, chat| chat.busy || chat.maybe_next_at.max(chat.maybe_retry_at).is_some_and(|ready_at| ready_at > now));
+            match maybe_wake_at {
+                Some(wake_at) =>
+                    Next::WaitUntil(wake_at),
+                None =>
+                    Next::Wait,
+            }
+        },
+    }
+}
+
+async fn perform(shared: Arc<Shared>, job: Job) {
+    let result = job.operation.perform(shared.client.as_ref()).await;
+    let chat_id = job.operation.chat_id();
+    let outcome = match &result {
+        Ok(..) =>
+            "ok",
+        Err(error) if error.retry_after().is_some() =>
+            "flood_control",
+        Err(..) =>
+            "error",
+    };
+    metrics::TELEGRAM_REQUESTS.with_label_values(&[job.operation.method(), outcome]).inc();
+    let mut state = shared.state.lock().unwrap();
+    let chat = state.chats.entry(chat_id).or_default();
+    chat.busy = false;
+    match result {
+        Err(error) if job.attempt < shared.max_attempts && error.retry_after().is_some() => {
+            let retry_after = error.retry_after().unwrap_or_default();
+            log::warn!("flood control in chat {}, retrying in {:?} (attempt {})", chat_id, retry_after, job.attempt);
+            chat.maybe_retry_at = Some(Instant::now() + retry_after);
+            let job = Job { attempt: job.attempt + 1, ..job };
+            // back to the head of the queue: keeps the order in the chat
+            match job.operation.priority() {
+                Priority::High =>
+                    state.high.push_front(job),
+                Priority::Low =>
+                    state.low.push_front(job),
+            }
+            report_depth(&state);
+        },
+        result => {
+            job.result_tx.send(result).ok();
+        },
+    }
+    drop(state);
+    shared.notify.notify_one();
+}
+
+fn report_depth(state: &State) {
+    metrics::SEND_QUEUE_DEPTH.with_label_values(&["high"]).set(state.high.len() as i64);
+    metrics::SEND_QUEUE_DEPTH.with_label_values(&["low"]).set(state.low.len() as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+        },
+        time::{
+            Duration,
+        },
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use tokio::{
+        time::{
+            Instant,
+        },
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            MessageId,
+        },
+    };
+
+    use super::{
+        CliArgs,
+        SendQueue,
+    };
+
+    use crate::{
+        telegram_client::{
+            fake::{
+                Call,
+                Method,
+                FakeClient,
+            },
+            TelegramClient,
+            OutgoingMessage,
+        },
+    };
+
+    fn setup(args: &[&str]) -> (Arc<FakeClient>, Arc<SendQueue>) {
+        let cli_args = CliArgs::parse_from([&["test"], args].concat());
+        let fake = Arc::new(FakeClient::new());
+        let queue = Arc::new(SendQueue::new(fake.clone(), &cli_args));
+        (fake, queue)
+    }
+
+    fn message(chat_id: i64, text: &str) -> OutgoingMessage {
+        OutgoingMessage::new(ChatId::new(chat_id), text.to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_interval_and_retry_after() {
+        let (fake, queue) = setup(&["--send-chat-interval-ms", "3000"]);
+        let started_at = Instant::now();
+        queue.send_message(message(-1, "first")).await.unwrap();
+        queue.send_message(message(-2, "other chat")).await.unwrap();
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        queue.send_message(message(-1, "second")).await.unwrap();
+        assert!(started_at.elapsed() >= Duration::from_secs(3));
+
+        fake.fail_next(Method::Send, "Too Many Requests: retry after 10");
+        let retried_at = Instant::now();
+        queue.send_message(message(-3, "flood")).await.unwrap();
+        assert!(retried_at.elapsed() >= Duration::from_secs(10));
+        assert_eq!(fake.calls().len(), 5);
+        assert_eq!(queue.depth().high + queue.depth().low, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_go_before_probes() {
+        let (fake, queue) = setup(&["--send-global-rate-per-s", "1", "--send-chat-interval-ms", "0"]);
+        let mut probes = Vec::new();
+        for message_id in 1 ..= 3 {
+            let queue = queue.clone();
+            probes.push(tokio::spawn(async move {
+                queue.forward_message(ChatId::new(-2), ChatId::new(-1), MessageId::new(message_id), true).await
+            }));
+            tokio::task::yield_now().await;
+        }
+        queue.send_message(message(-1, "reply")).await.unwrap();
+        for probe in probes {
+            probe.await.unwrap().unwrap();
+        }
+
+        let calls = fake.calls();
+        assert!(matches!(&calls[0], Call::Forward { .. }));
+        assert_eq!(calls[1], Call::Send(message(-1, "reply")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deletes_are_not_held_by_chat_interval() {
+        let (fake, queue) = setup(&["--send-chat-interval-ms", "3000"]);
+        let started_at = Instant::now();
+        let copy_id = queue.forward_message(ChatId::new(-3), ChatId::new(-1), MessageId::new(10), true).await.unwrap();
+        queue.delete_message(ChatId::new(-3), copy_id).await.unwrap();
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        queue.forward_message(ChatId::new(-3), ChatId::new(-1), MessageId::new(11), true).await.unwrap();
+        assert!(started_at.elapsed() >= Duration::from_secs(3));
+
+        // flood control holds deletes as well
+        fake.fail_next(Method::Delete, "Too Many Requests: retry after 10");
+        let retried_at = Instant::now();
+        queue.delete_message(ChatId::new(-3), copy_id).await.unwrap();
+        assert!(retried_at.elapsed() >= Duration::from_secs(10));
+        assert_eq!(fake.calls().len(), 5);
+    }
+}
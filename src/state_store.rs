@@ -0,0 +1,229 @@
+use std::{
+    io,
+    fs,
+    sync::{
+        Mutex,
+    },
+    path::{
+        PathBuf,
+    },
+    collections::{
+        BTreeMap,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use serde::{
+    de::{
+        DeserializeOwned,
+    },
+    Serialize,
+    Deserialize,
+};
+
+pub const DEFAULT_STATE_FILE: &str = "beercan-state.json";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// file to keep module state in between restarts
+    #[clap(long = "state-file", default_value = DEFAULT_STATE_FILE)]
+    state_file: PathBuf,
+}
+
+/// Value kept in the store. `VERSION` is saved along with the value: a value
+/// saved with an older version is passed through `migrate` on load.
+pub trait Versioned: Serialize + DeserializeOwned {
+    const VERSION: u32;
+
+    /// Converts a value saved with `from_version` to the current schema,
+    /// `None` if the value cannot be converted.
+    fn migrate(_from_version: u32, _value: serde_json::Value) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile { path: PathBuf, error: io::Error, },
+    ParseFile { path: PathBuf, error: serde_json::Error, },
+    WriteFile { path: PathBuf, error: io::Error, },
+    Encode { key: String, error: serde_json::Error, },
+    Decode { key: String, error: serde_json::Error, },
+    NewerVersion { key: String, version: u32, supported: u32, },
+    Migrate { key: String, version: u32, },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Record {
+    version: u32,
+    value: serde_json::Value,
+}
+
+/// File-backed key value store. The whole store is a json object which is
+/// rewritten atomically on every `save`.
+pub struct StateStore {
+    maybe_path: Option<PathBuf>,
+    records: Mutex<BTreeMap<String, Record>>,
+}
+
+impl StateStore {
+    pub fn open(cli_args: &CliArgs) -> Result<StateStore, Error> {
+        let path = cli_args.state_file.clone();
+        let records = match fs::read_to_string(&path) {
+            Ok(contents) =>
+                serde_json::from_str(&contents)
+                    .map_err(|error| Error::ParseFile { path: path.clone(), error, })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                log::info!("state file {:?} does not exist, starting with an empty state", path);
+                BTreeMap::new()
+            },
+            Err(error) =>
+                return Err(Error::ReadFile { path, error, }),
+        };
+        Ok(StateStore {
+            maybe_path: Some(path),
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Keeps the loaded state but never writes it back, changes live in memory
+    /// only: `--dry-run` does not touch the state of the real bot.
+    pub fn into_read_only(self) -> StateStore {
+        StateStore {
+            maybe_path: None,
+            ..self
+        }
+    }
+
+    /// Store which is never written to disk.
+    pub fn in_memory() -> StateStore {
+        StateStore {
+            maybe_path: None,
+            records: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn load<T>(&self, key: &str) -> Result<Option<T>, Error> where T: Versioned {
+        let Record { version, value, } = match self.records.lock().unwrap().get(key) {
+            Some(record) =>
+                record.clone(),
+            None =>
+                return Ok(None),
+        };
+        let value = if version == T::VERSION {
+            value
+        } else if version > T::VERSION {
+            return Err(Error::NewerVersion { key: key.to_string(), version, supported: T::VERSION, });
+        } else {
+            log::info!("migrating state {:?} from version {} to {}", key, version, T::VERSION);
+            T::migrate(version, value)
+                .ok_or_else(|| Error::Migrate { key: key.to_string(), version, })?
+        };
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(|error| Error::Decode { key: key.to_string(), error, })
+    }
+
+    pub fn save<T>(&self, key: &str, value: &T) -> Result<(), Error> where T: Versioned {
+        let value = serde_json::to_value(value)
+            .map_err(|error| Error::Encode { key: key.to_string(), error, })?;
+        let mut records = self.records.lock().unwrap();
+        records.insert(key.to_string(), Record { version: T::VERSION, value, });
+        self.flush(&records)
+    }
+
+    fn flush(&self, records: &BTreeMap<String, Record>) -> Result<(), Error> {
+        let path = match &self.maybe_path {
+            Some(path) =>
+                path,
+            None =>
+                return Ok(()),
+        };
+        let contents = serde_json::to_string_pretty(records)
+            .map_err(|error| Error::Encode { key: String::new(), error, })?;
+        // write and rename, so a crash never leaves a truncated state file
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|()| fs::rename(&tmp_path, path))
+            .map_err(|error| Error::WriteFile { path: path.clone(), error, })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{
+        Serialize,
+        Deserialize,
+    };
+
+    use clap::{
+        Parser,
+    };
+
+    use super::{
+        Error,
+        CliArgs,
+        Versioned,
+        StateStore,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct CounterV1 {
+        count: u64,
+    }
+
+    impl Versioned for CounterV1 {
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct CounterV2 {
+        count: u64,
+        total: u64,
+    }
+
+    impl Versioned for CounterV2 {
+        const VERSION: u32 = 2;
+
+        fn migrate(from_version: u32, mut value: serde_json::Value) -> Option<serde_json::Value> {
+            match from_version {
+                1 => {
+                    value["total"] = value["count"].clone();
+                    Some(value)
+                },
+                _ =>
+                    None,
+            }
+        }
+    }
+
+    #[test]
+    fn save_load_across_reopen() {
+        let path = std::env::temp_dir().join(format!("beercan-state-test-{}.json", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let cli_args = CliArgs::parse_from(["test", "--state-file", path_str]);
+
+        let store = StateStore::open(&cli_args).unwrap();
+        assert_eq!(store.load::<CounterV1>("counter").unwrap(), None);
+        store.save("counter", &CounterV1 { count: 3, }).unwrap();
+
+        let store = StateStore::open(&cli_args).unwrap();
+        assert_eq!(store.load::<CounterV1>("counter").unwrap(), Some(CounterV1 { count: 3, }));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn schema_versions() {
+        let store = StateStore::in_memory();
+        store.save("counter", &CounterV1 { count: 3, }).unwrap();
+        assert_eq!(store.load::<CounterV2>("counter").unwrap(), Some(CounterV2 { count: 3, total: 3, }));
+
+        store.save("counter", &CounterV2 { count: 1, total: 4, }).unwrap();
+        assert!(matches!(store.load::<CounterV1>("counter"), Err(Error::NewerVersion { version: 2, supported: 1, .. })));
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    io,
+    sync::{
+        Mutex,
+        atomic::{
+            AtomicI64,
+            Ordering,
+        },
+    },
+    time::{
+        Duration,
+    },
+};
+
+use hyper::{
+    client::{
+        HttpConnector,
+    },
+    header,
+    Body,
+    Client,
+    Request,
+};
+
+use hyper_tls::{
+    HttpsConnector,
+};
+
+use serde::{
+    Deserialize,
+};
+
+use serde_json::{
+    json,
+};
+
+use telegram_bot::{
+    types::{
+        ChatId,
+        Integer,
+        MessageId,
+    },
+    Api,
+    ParseMode,
+    ToMessageId,
+    DeleteMessage,
+    ForwardMessage,
+};
+
+use crate::{
+    polling,
+};
+
+/// `sendMessage` parameters used by the modules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingMessage {
+    pub chat_id: ChatId,
+    pub text: String,
+    pub maybe_parse_mode: Option<ParseMode>,
+    pub maybe_reply_to: Option<MessageId>,
+    /// forum topic to post in (`message_thread_id`)
+    pub maybe_message_thread_id: Option<Integer>,
+}
+
+impl OutgoingMessage {
+    pub fn new(chat_id: ChatId, text: String) -> OutgoingMessage {
+        OutgoingMessage {
+            chat_id,
+            text,
+            maybe_parse_mode: None,
+            maybe_reply_to: None,
+            maybe_message_thread_id: None,
+        }
+    }
+
+    /// `sendMessage` parameters as the Bot API takes them.
+    fn to_params(&self) -> serde_json::Value {
+        let mut params = json!({
+            "chat_id": Integer::from(self.chat_id),
+            "text": self.text,
+        });
+        if let Some(parse_mode) = self.maybe_parse_mode {
+            params["parse_mode"] = parse_mode_name(parse_mode).into();
+        }
+        if let Some(reply_to) = self.maybe_reply_to {
+            params["reply_to_message_id"] = Integer::from(reply_to).into();
+        }
+        if let Some(message_thread_id) = self.maybe_message_thread_id {
+            params["message_thread_id"] = message_thread_id.into();
+        }
+        params
+    }
+}
+
+fn parse_mode_name(parse_mode: ParseMode) -> &'static str {
+    match parse_mode {
+        ParseMode::Markdown =>
+            "Markdown",
+        ParseMode::MarkdownV2 =>
+            "MarkdownV2",
+        ParseMode::Html =>
+            "HTML",
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    TelegramApi(telegram_bot::Error),
+    RequestBuild(hyper::http::Error),
+    Transport(hyper::Error),
+    ResponseBody(hyper::Error),
+    ResponseDecode(serde_json::Error),
+    /// error returned by the Bot API for a request made without `telegram_bot`
+    Api {
+        description: String,
+    },
+    /// error returned by `FakeClient`
+    Scripted {
+        description: String,
+    },
+    /// the request was queued but the send queue is gone
+    SendQueueTerminated,
+}
+
+impl Error {
+    /// Forward of a message which has been deleted.
+    pub fn is_message_to_forward_not_found(&self) -> bool {
+        self.description().contains("message to forward not found")
+    }
+
+    /// Flood control delay: "Too Many Requests: retry after N".
+    pub fn retry_after(&self) -> Option<Duration> {
+        let description = self.description();
+        let (_, tail) = description.split_once("retry after ")?;
+        let seconds = tail.split(|ch: char| !ch.is_ascii_digit()).next()?;
+        seconds.parse().ok().map(Duration::from_secs)
+    }
+
+    fn description(&self) -> String {
+        match self {
+            Error::TelegramApi(error) =>
+                error.to_string(),
+            Error::RequestBuild(error) =>
+                error.to_string(),
+            Error::Transport(error) | Error::ResponseBody(error) =>
+                error.to_string(),
+            Error::ResponseDecode(error) =>
+                error.to_string(),
+            Error::Api { description, } |
+            Error::Scripted { description, } =>
+                description.clone(),
+            Error::SendQueueTerminated =>
+                "send queue is terminated".to_string(),
+        }
+    }
+}
+
+/// Telegram operations used by the modules.
+#[async_trait::async_trait]
+pub trait TelegramClient: Send + Sync {
+    async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, Error>;
+
+    async fn forward_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    )
+        -> Result<MessageId, Error>;
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), Error>;
+}
+
+#[derive(Deserialize)]
+struct SendMessageResponse {
+    ok: bool,
+    description: Option<String>,
+    result: Option<SentMessage>,
+}
+
+#[derive(Deserialize)]
+struct SentMessage {
+    message_id: Integer,
+}
+
+/// Production client on top of `telegram_bot::Api`. `sendMessage` is made
+/// directly: `telegram_bot` has no `message_thread_id`.
+pub struct ApiClient {
+    api: Api,
+    client: Client<HttpsConnector<HttpConnector>>,
+    send_message_url: String,
+}
+
+impl ApiClient {
+    pub fn new(telegram_bot_token: &str, telegram_api_url: &str) -> ApiClient {
+        ApiClient {
+            api: Api::new(telegram_bot_token),
+            client: Client::builder()
+                .build(HttpsConnector::new()),
+            send_message_url: polling::method_url(telegram_api_url, telegram_bot_token, "sendMessage"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TelegramClient for ApiClient {
+    async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, Error> {
+        let request = Request::post(&self.send_message_url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(message.to_params().to_string()))
+            .map_err(Error::RequestBuild)?;
+        let response = self.client.request(request).await
+            .map_err(Error::Transport)?;
+        let body = hyper::body::to_bytes(response.into_body()).await
+            .map_err(Error::ResponseBody)?;
+        let response: SendMessageResponse = serde_json::from_slice(&body)
+            .map_err(Error::ResponseDecode)?;
+        match response {
+            SendMessageResponse { ok: true, result: Some(sent_message), .. } =>
+                Ok(MessageId::new(sent_message.message_id)),
+            SendMessageResponse { description, .. } =>
+                Err(Error::Api { description: description.unwrap_or_default(), }),
+        }
+    }
+
+    async fn forward_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    )
+        -> Result<MessageId, Error>
+    {
+        let mut forward_message = ForwardMessage::new(message_id, from_chat_id, chat_id);
+        if disable_notification {
+            forward_message.disable_notification();
+        }
+        let message_or_channel_post = self.api.send(forward_message).await
+            .map_err(Error::TelegramApi)?;
+        Ok(message_or_channel_post.to_message_id())
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), Error> {
+        self.api.send(DeleteMessage::new(chat_id, message_id)).await
+            .map_err(Error::TelegramApi)
+    }
+}
+
+/// Telegram request made by the modules, recorded by `FakeClient`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    Send(OutgoingMessage),
+    Forward {
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    },
+    Delete {
+        chat_id: ChatId,
+        message_id: MessageId,
+    },
+}
+
+impl Call {
+    /// Bot API method with its parameters.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Call::Send(message) => {
+                let mut params = message.to_params();
+                params["method"] = "sendMessage".into();
+                params
+            },
+            Call::Forward { chat_id, from_chat_id, message_id, disable_notification, } =>
+                json!({
+                    "method": "forwardMessage",
+                    "chat_id": Integer::from(*chat_id),
+                    "from_chat_id": Integer::from(*from_chat_id),
+                    "message_id": Integer::from(*message_id),
+                    "disable_notification": disable_notification,
+                }),
+            Call::Delete { chat_id, message_id, } =>
+                json!({
+                    "method": "deleteMessage",
+                    "chat_id": Integer::from(*chat_id),
+                    "message_id": Integer::from(*message_id),
+                }),
+        }
+    }
+}
+
+/// `--dry-run` client: every request is printed to stdout as a json line
+/// instead of being sent, whatever the log level is. Sends and forwards
+/// succeed with made up message ids.
+pub struct DryRunClient {
+    out: Mutex<Box<dyn io::Write + Send>>,
+    last_message_id: AtomicI64,
+}
+
+impl DryRunClient {
+    pub fn new() -> DryRunClient {
+        DryRunClient::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(out: Box<dyn io::Write + Send>) -> DryRunClient {
+        DryRunClient {
+            out: Mutex::new(out),
+            last_message_id: AtomicI64::new(0),
+        }
+    }
+
+    fn print(&self, call: Call) -> MessageId {
+        let mut out = self.out.lock().unwrap();
+        if let Err(error) = writeln!(out, "{}", call.to_json()).and_then(|()| out.flush()) {
+            log::error!("failed to print dry run request: {:?}", error);
+        }
+        MessageId::new(self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+#[async_trait::async_trait]
+impl TelegramClient for DryRunClient {
+    async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, Error> {
+        Ok(self.print(Call::Send(message)))
+    }
+
+    async fn forward_message(
+        &self,
+        chat_id: ChatId,
+        from_chat_id: ChatId,
+        message_id: MessageId,
+        disable_notification: bool,
+    )
+        -> Result<MessageId, Error>
+    {
+        Ok(self.print(Call::Forward { chat_id, from_chat_id, message_id, disable_notification, }))
+    }
+
+    async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), Error> {
+        self.print(Call::Delete { chat_id, message_id, });
+        Ok(())
+    }
+}
+
+/// In-memory client for tests and `replay`.
+pub mod fake {
+    use std::{
+        sync::{
+            Mutex,
+        },
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            MessageId,
+            Integer,
+        },
+    };
+
+    pub use super::{
+        Call,
+    };
+
+    use super::{
+        Error,
+        TelegramClient,
+        OutgoingMessage,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Method {
+        Send,
+        Forward,
+        Delete,
+    }
+
+    #[derive(Default)]
+    struct FakeState {
+        calls: Vec<Call>,
+        scripted_errors: Vec<(Method, String)>,
+        last_message_id: Integer,
+    }
+
+    /// In-memory client which records every call. Calls succeed unless an error
+    /// is scripted with `fail_next`.
+    #[derive(Default)]
+    pub struct FakeClient {
+        state: Mutex<FakeState>,
+    }
+
+    impl FakeClient {
+        pub fn new() -> FakeClient {
+            FakeClient::default()
+        }
+
+        /// The next `method` call fails with an error with `description`.
+        #[cfg(test)]
+        pub fn fail_next(&self, method: Method, description: &str) {
+            self.state.lock().unwrap().scripted_errors.push((method, description.to_string()));
+        }
+
+        pub fn calls(&self) -> Vec<Call> {
+            self.state.lock().unwrap().calls.clone()
+        }
+
+        fn call(&self, method: Method, call: Call) -> Result<MessageId, Error> {
+            let mut state = self.state.lock().unwrap();
+            state.calls.push(call);
+            if let Some(index) = state.scripted_errors.iter().position(|(scripted_method, _)| *scripted_method == method) {
+                let (_, description) = state.scripted_errors.remove(index);
+                return Err(Error::Scripted { description, });
+            }
+            state.last_message_id += 1;
+            Ok(MessageId::new(state.last_message_id))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl TelegramClient for FakeClient {
+        async fn send_message(&self, message: OutgoingMessage) -> Result<MessageId, Error> {
+            self.call(Method::Send, Call::Send(message))
+        }
+
+        async fn forward_message(
+            &self,
+            chat_id: ChatId,
+            from_chat_id: ChatId,
+            message_id: MessageId,
+            disable_notification: bool,
+        )
+            -> Result<MessageId, Error>
+        {
+            self.call(Method::Forward, Call::Forward { chat_id, from_chat_id, message_id, disable_notification, })
+        }
+
+        async fn delete_message(&self, chat_id: ChatId, message_id: MessageId) -> Result<(), Error> {
+            self.call(Method::Delete, Call::Delete { chat_id, message_id, })
+                .map(|_message_id| ())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            Arc,
+            Mutex,
+        },
+        time::{
+            Duration,
+        },
+    };
+
+    use telegram_bot::{
+        types::{
+            ChatId,
+            MessageId,
+        },
+        ParseMode,
+    };
+
+    use super::{
+        fake::{
+            Call,
+            Method,
+            FakeClient,
+        },
+        DryRunClient,
+        TelegramClient,
+        OutgoingMessage,
+    };
+
+    #[tokio::test]
+    async fn fake_records_calls_and_scripted_errors() {
+        let client = FakeClient::new();
+        client.fail_next(Method::Forward, "Bad Request: message to forward not found");
+
+        let error = client.forward_message(ChatId::new(-2), ChatId::new(-1), MessageId::new(7), true).await.unwrap_err();
+        assert!(error.is_message_to_forward_not_found());
+        assert_eq!(error.retry_after(), None);
+        client.forward_message(ChatId::new(-2), ChatId::new(-1), MessageId::new(7), true).await.unwrap();
+        client.send_message(OutgoingMessage::new(ChatId::new(-1), "hello".to_string())).await.unwrap();
+
+        let calls = client.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0], calls[1]);
+        assert_eq!(calls[2], Call::Send(OutgoingMessage::new(ChatId::new(-1), "hello".to_string())));
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_parsed() {
+        let client = FakeClient::new();
+        client.fail_next(Method::Send, "Too Many Requests: retry after 35");
+        let error = client.send_message(OutgoingMessage::new(ChatId::new(-1), "hello".to_string())).await.unwrap_err();
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(35)));
+    }
+
+    #[test]
+    fn send_json_has_set_params_only() {
+        let mut message = OutgoingMessage::new(ChatId::new(-1), "hello".to_string());
+        message.maybe_parse_mode = Some(ParseMode::Html);
+        message.maybe_message_thread_id = Some(5);
+        assert_eq!(Call::Send(message).to_json(), serde_json::json!({
+            "method": "sendMessage",
+            "chat_id": -1,
+            "text": "hello",
+            "parse_mode": "HTML",
+            "message_thread_id": 5,
+        }));
+    }
+
+    /// Output shared with the test, `DryRunClient` owns its writer.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_sends_nothing() {
+        let output = Output::default();
+        let client = DryRunClient::with_output(Box::new(output.clone()));
+        let message = OutgoingMessage::new(ChatId::new(-1), "hello".to_string());
+        let first = client.send_message(message.clone()).await.unwrap();
+        let second = client.forward_message(ChatId::new(-2), ChatId::new(-1), MessageId::new(7), true).await.unwrap();
+        assert_ne!(first, second);
+        client.delete_message(ChatId::new(-2), second).await.unwrap();
+
+        let printed = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let printed: Vec<serde_json::Value> = printed.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected = [
+            Call::Send(message),
+            Call::Forward { chat_id: ChatId::new(-2), from_chat_id: ChatId::new(-1), message_id: MessageId::new(7), disable_notification: true, },
+            Call::Delete { chat_id: ChatId::new(-2), message_id: second, },
+        ];
+        assert_eq!(printed, expected.iter().map(Call::to_json).collect::<Vec<_>>());
+    }
+}
@@ -1,4 +1,10 @@
 
+use std::{
+    collections::{
+        HashSet,
+    },
+};
+
 use clap::{
     Parser,
     AppSettings,
@@ -6,68 +12,121 @@ use clap::{
 
 use rand::Rng;
 
+use serde::{
+    Deserialize,
+};
+
 use telegram_bot::{
     types::{
         UserId,
-        GroupId,
+        ChatId,
         Integer,
     },
-    Api,
     User,
-    Group,
-    Update,
     Message,
     UpdateKind,
     MessageChat,
     MessageKind,
-    CanReplySendMessage,
 };
 
-pub const DEFAULT_USER_ID_STR: &'static str = "337229462"; // Parviz Sadesi
-pub const DEFAULT_GROUP_ID_STR: &'static str = "-222927743"; // Beercan
+use crate::{
+    bot_module,
+    telegram_client::{
+        self,
+        TelegramClient,
+        OutgoingMessage,
+    },
+};
 
 #[derive(Clone, Debug, Parser)]
 #[clap(setting = AppSettings::DeriveDisplayOrder)]
 pub struct CliArgs {
+    /// user id to remind about vaccination (overrides config)
+    #[clap(long = "vaccine-reminder-user-id", allow_hyphen_values = true)]
+    vaccine_reminder_user_id: Option<Integer>,
+
+    /// group id to use (overrides config)
+    #[clap(long = "vaccine-reminder-group-id", allow_hyphen_values = true)]
+    vaccine_reminder_group_id: Option<Integer>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
     /// user id to remind about vaccination
-    #[clap(long = "vaccine-reminder-user-id", default_value = DEFAULT_USER_ID_STR, allow_hyphen_values = true)]
-    vaccine_reminder_user_id: Integer,
+    pub user_id: Integer,
+    /// group or supergroup id to use
+    pub group_id: Integer,
+}
 
-    /// group id to use
-    #[clap(long = "vaccine-reminder-group-id", default_value = DEFAULT_GROUP_ID_STR, allow_hyphen_values = true)]
-    vaccine_reminder_group_id: Integer,
+impl CliArgs {
+    /// Applies command line overrides on top of the first `[[vaccine_reminder]]`
+    /// config instance. An instance is created from the command line alone if
+    /// the config has none.
+    pub fn override_config(&self, mut configs: Vec<Config>) -> Result<Vec<Config>, Error> {
+        if configs.is_empty() {
+            if self.vaccine_reminder_user_id.is_none() && self.vaccine_reminder_group_id.is_none() {
+                return Ok(configs);
+            }
+            configs.push(Config {
+                user_id: self.vaccine_reminder_user_id.ok_or(Error::MissingUserId)?,
+                group_id: self.vaccine_reminder_group_id.ok_or(Error::MissingGroupId)?,
+            });
+        }
+        let config = &mut configs[0];
+        if let Some(user_id) = self.vaccine_reminder_user_id {
+            config.user_id = user_id;
+        }
+        if let Some(group_id) = self.vaccine_reminder_group_id {
+            config.group_id = group_id;
+        }
+        let mut instances = HashSet::new();
+        for config in configs.iter() {
+            if !instances.insert((config.group_id, config.user_id)) {
+                return Err(Error::DuplicateInstance { user_id: config.user_id, group_id: config.group_id, });
+            }
+        }
+        Ok(configs)
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
-    TelegramApiSend(telegram_bot::Error),
+    MissingUserId,
+    MissingGroupId,
+    DuplicateInstance { user_id: Integer, group_id: Integer, },
+    TelegramApiSend(telegram_client::Error),
 }
 
 pub struct VaccineReminder {
+    name: String,
     user_id: UserId,
-    group_id: GroupId,
+    chat_id: ChatId,
 }
 
 impl VaccineReminder {
-    pub fn new(cli_args: &CliArgs) -> Result<VaccineReminder, Error> {
-        Ok(VaccineReminder {
-            user_id: cli_args.vaccine_reminder_user_id.into(),
-            group_id: cli_args.vaccine_reminder_group_id.into(),
-        })
+    pub fn new(config: &Config) -> VaccineReminder {
+        VaccineReminder {
+            name: format!("vaccine_reminder#{}/{}", config.group_id, config.user_id),
+            user_id: config.user_id.into(),
+            chat_id: config.group_id.into(),
+        }
     }
 
-    pub async fn process(&mut self, update: &Update, api: &Api) -> Result<(), Error> {
+    pub async fn process(&mut self, update: &bot_module::IncomingUpdate, client: &dyn TelegramClient) -> Result<(), Error> {
         match &update.kind {
             UpdateKind::Message(message) =>
                 match message {
                     Message {
                         from: User { id: user_id, .. },
-                        chat: MessageChat::Group(Group { id: chat_id, .. }),
+                        chat: chat @ (MessageChat::Group(..) | MessageChat::Supergroup(..)),
                         kind: MessageKind::Text { data, .. },
                         ..
-                    } if user_id == &self.user_id && chat_id == &self.group_id && is_question(data) => {
-                        let reply_phrase = build_phrase();
-                        let _message_or_channel_post = api.send(message.text_reply(reply_phrase)).await
+                    } if user_id == &self.user_id && chat.id() == self.chat_id && is_question(data) => {
+                        let mut reply = OutgoingMessage::new(chat.id(), build_phrase());
+                        reply.maybe_reply_to = Some(message.id);
+                        reply.maybe_message_thread_id = update.maybe_message_thread_id;
+                        let _message_id = client.send_message(reply).await
                             .map_err(Error::TelegramApiSend)?;
                     },
                     other_message =>
@@ -80,6 +139,33 @@ impl VaccineReminder {
     }
 }
 
+impl From<Error> for bot_module::Error {
+    fn from(error: Error) -> bot_module::Error {
+        bot_module::Error::new(error)
+    }
+}
+
+#[async_trait::async_trait]
+impl bot_module::BotModule for VaccineReminder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn handle_update(&mut self, update: &bot_module::IncomingUpdate, context: &bot_module::Context) -> Result<(), bot_module::Error> {
+        self.process(update, context.client.as_ref()).await?;
+        Ok(())
+    }
+
+    fn migrate_chat(&mut self, from: ChatId, to: ChatId) -> bool {
+        if self.chat_id == from {
+            self.chat_id = to;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 fn is_question(message: &str) -> bool {
     for ch in message.chars().rev() {
         if ch == '?' {
@@ -233,10 +319,118 @@ fn random_variant<R>(rng: &mut R, variants: &[&'static str]) -> &'static str whe
 
 #[cfg(test)]
 mod tests {
+    use telegram_bot::{
+        types::{
+            ChatId,
+            GroupId,
+            SupergroupId,
+            MessageId,
+        },
+        User,
+        Update,
+        Message,
+        UpdateKind,
+        MessageChat,
+        MessageKind,
+        Group,
+        Supergroup,
+    };
+
     use super::{
         is_question,
+        Config,
+        VaccineReminder,
+    };
+
+    use crate::{
+        bot_module::{
+            BotModule,
+            IncomingUpdate,
+        },
+        telegram_client::{
+            fake::{
+                Call,
+                FakeClient,
+            },
+        },
     };
 
+    fn question(id: i64, chat: MessageChat) -> Update {
+        let message = Message {
+            id: MessageId::new(id),
+            from: User {
+                id: 7.into(),
+                first_name: "Ahmed".to_string(),
+                last_name: None,
+                username: None,
+                is_bot: false,
+                language_code: None,
+            },
+            date: 0,
+            chat,
+            forward: None,
+            reply_to_message: None,
+            edit_date: None,
+            media_group_id: None,
+            kind: MessageKind::Text { data: "как дела?".to_string(), entities: Vec::new(), },
+        };
+        Update { id, kind: UpdateKind::Message(message), }
+    }
+
+    #[tokio::test]
+    async fn question_in_migrated_supergroup_is_answered() {
+        let client = FakeClient::new();
+        let mut reminder = VaccineReminder::new(&Config { user_id: 7, group_id: -1, });
+        let group = MessageChat::Group(Group {
+            id: GroupId::new(-1),
+            title: "beercan".to_string(),
+            all_members_are_administrators: false,
+            invite_link: None,
+        });
+        let supergroup = MessageChat::Supergroup(Supergroup {
+            id: SupergroupId::new(-1001),
+            title: "beercan".to_string(),
+            username: None,
+            invite_link: None,
+        });
+
+        assert!(reminder.migrate_chat(ChatId::new(-1), ChatId::new(-1001)));
+        reminder.process(&question(1, group).into(), &client).await.unwrap();
+        reminder.process(&question(2, supergroup).into(), &client).await.unwrap();
+
+        let replies: Vec<_> = client.calls().into_iter()
+            .map(|call| match call {
+                Call::Send(message) =>
+                    (message.chat_id, message.maybe_reply_to),
+                other_call =>
+                    panic!("unexpected call: {:?}", other_call),
+            })
+            .collect();
+        assert_eq!(replies, vec![(ChatId::new(-1001), Some(MessageId::new(2)))]);
+    }
+
+    #[tokio::test]
+    async fn question_in_forum_topic_is_answered_in_topic() {
+        let client = FakeClient::new();
+        let mut reminder = VaccineReminder::new(&Config { user_id: 7, group_id: -1001, });
+        let supergroup = MessageChat::Supergroup(Supergroup {
+            id: SupergroupId::new(-1001),
+            title: "beercan".to_string(),
+            username: None,
+            invite_link: None,
+        });
+
+        let update = IncomingUpdate { update: question(1, supergroup), maybe_message_thread_id: Some(5), };
+        reminder.process(&update, &client).await.unwrap();
+
+        match client.calls().as_slice() {
+            [Call::Send(reply)] =>
+                assert_eq!(reply.maybe_message_thread_id, Some(5)),
+            calls =>
+                panic!("unexpected calls: {:?}", calls),
+        }
+    }
+
     #[test]
     fn question_00() {
         assert!(is_question("Вера это младшая ?"));
@@ -0,0 +1,445 @@
+use std::{
+    io,
+    fs,
+    convert::Infallible,
+    net::{
+        SocketAddr,
+    },
+    path::{
+        PathBuf,
+    },
+    sync::{
+        Arc,
+    },
+};
+
+use clap::{
+    Parser,
+    AppSettings,
+};
+
+use hyper::{
+    client::{
+        HttpConnector,
+    },
+    server::{
+        conn::{
+            Http,
+        },
+    },
+    service::{
+        service_fn,
+    },
+    header,
+    Body,
+    Client,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+
+use hyper_tls::{
+    HttpsConnector,
+};
+
+use serde::{
+    Deserialize,
+};
+
+use tokio::{
+    net::{
+        TcpListener,
+    },
+    sync::{
+        mpsc,
+    },
+    task::{
+        JoinHandle,
+    },
+};
+
+use tokio_native_tls::{
+    native_tls,
+    TlsAcceptor,
+};
+
+use telegram_bot::{
+    types::{
+        Integer,
+    },
+};
+
+use crate::{
+    polling::{
+        self,
+        ReceivedUpdate,
+    },
+};
+
+pub const DEFAULT_WEBHOOK_LISTEN_ADDR: &str = "127.0.0.1:8080";
+pub const DEFAULT_WEBHOOK_PATH: &str = "/";
+pub const DEFAULT_WEBHOOK_QUEUE_SIZE_STR: &str = "128";
+
+/// Header Telegram puts the `secret_token` of `setWebhook` into.
+const SECRET_TOKEN_HEADER: &str = "x-telegram-bot-api-secret-token";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(setting = AppSettings::DeriveDisplayOrder)]
+pub struct CliArgs {
+    /// address to listen for webhook requests on
+    #[clap(long = "webhook-listen-addr", default_value = DEFAULT_WEBHOOK_LISTEN_ADDR)]
+    webhook_listen_addr: SocketAddr,
+
+    /// http path of the webhook
+    #[clap(long = "webhook-path", default_value = DEFAULT_WEBHOOK_PATH)]
+    webhook_path: String,
+
+    /// secret token expected in `X-Telegram-Bot-Api-Secret-Token` header (required in webhook mode)
+    #[clap(long = "webhook-secret-token")]
+    webhook_secret_token: Option<String>,
+
+    /// public webhook url to register with `setWebhook` on start (register it manually if omitted)
+    #[clap(long = "webhook-url")]
+    webhook_url: Option<String>,
+
+    /// PKCS#12 certificate with private key to serve https (plain http if omitted, e.g. behind a reverse proxy)
+    #[clap(long = "webhook-tls-identity")]
+    webhook_tls_identity: Option<PathBuf>,
+
+    /// password of the PKCS#12 certificate
+    #[clap(long = "webhook-tls-password", default_value = "")]
+    webhook_tls_password: String,
+
+    /// updates received but not dispatched yet, requests are answered with 503 and retried by Telegram when the queue is full
+    #[clap(long = "webhook-queue-size", default_value = DEFAULT_WEBHOOK_QUEUE_SIZE_STR)]
+    webhook_queue_size: usize,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingSecretToken,
+    InvalidSecretToken,
+    ReadTlsIdentity { path: PathBuf, error: io::Error, },
+    TlsIdentity(native_tls::Error),
+    Bind { addr: SocketAddr, error: io::Error, },
+    SetWebhookRequestBuild(hyper::http::Error),
+    SetWebhookTransport(hyper::Error),
+    SetWebhookResponseBody(hyper::Error),
+    SetWebhookResponseDecode(serde_json::Error),
+    SetWebhook { description: String, },
+    ServerTerminated,
+}
+
+#[derive(Deserialize)]
+struct SetWebhookResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+/// Receives updates pushed by Telegram. Updates are queued by the http
+/// server and taken by `next_batch` in the order of arrival. An update is
+/// answered with 200 only once it is queued, so it must not be lost after
+/// that: `stop` hands the queued ones over before the webhook goes away.
+pub struct Webhook {
+    updates_rx: mpsc::Receiver<ReceivedUpdate>,
+    server_task: JoinHandle<()>,
+    maybe_last_update_id: Option<Integer>,
+}
+
+impl Webhook {
+    /// On `dry_run` the `setWebhook` request is printed to stdout instead of being sent.
+    pub async fn start(telegram_bot_token: &str, telegram_api_url: &str, cli_args: &CliArgs, dry_run: bool) -> Result<Webhook, Error> {
+        let secret_token = cli_args.webhook_secret_token.clone()
+            .ok_or(Error::MissingSecretToken)?;
+        // the only characters Telegram accepts
+        let secret_token_is_valid = (1 ..= 256).contains(&secret_token.len())
+            && secret_token.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-');
+        if !secret_token_is_valid {
+            return Err(Error::InvalidSecretToken);
+        }
+
+        let maybe_tls_acceptor = match &cli_args.webhook_tls_identity {
+            Some(path) => {
+                let identity = fs::read(path)
+                    .map_err(|error| Error::ReadTlsIdentity { path: path.clone(), error, })?;
+                let identity = native_tls::Identity::from_pkcs12(&identity, &cli_args.webhook_tls_password)
+                    .map_err(Error::TlsIdentity)?;
+                let acceptor = native_tls::TlsAcceptor::new(identity)
+                    .map_err(Error::TlsIdentity)?;
+                Some(TlsAcceptor::from(acceptor))
+            },
+            None =>
+                None,
+        };
+
+        let listener = TcpListener::bind(cli_args.webhook_listen_addr).await
+            .map_err(|error| Error::Bind { addr: cli_args.webhook_listen_addr, error, })?;
+        log::info!("listening for webhook requests on {}", cli_args.webhook_listen_addr);
+
+        match &cli_args.webhook_url {
+            Some(webhook_url) if dry_run =>
+                // the secret token is left out: it is not for the terminal
+                println!("{}", serde_json::json!({ "method": "setWebhook", "url": webhook_url, })),
+            Some(webhook_url) => {
+                set_webhook(telegram_bot_token, telegram_api_url, webhook_url, &secret_token).await?;
+                log::info!("webhook is set to {:?}", webhook_url);
+            },
+            None =>
+                (),
+        }
+
+        let (updates_tx, updates_rx) = mpsc::channel(cli_args.webhook_queue_size.max(1));
+        let endpoint = Arc::new(Endpoint {
+            path: cli_args.webhook_path.clone(),
+            secret_token,
+            updates_tx,
+        });
+        let server_task = tokio::spawn(run_server(listener, maybe_tls_acceptor, endpoint));
+        Ok(Webhook {
+            updates_rx,
+            server_task,
+            maybe_last_update_id: None,
+        })
+    }
+
+    /// Marks the update as processed.
+    pub fn commit(&mut self, update_id: Integer) {
+        match self.maybe_last_update_id {
+            Some(last_update_id) if last_update_id >= update_id =>
+                (),
+            _ =>
+                self.maybe_last_update_id = Some(update_id),
+        }
+    }
+
+    /// Waits for the next updates, ones already processed (redelivered by Telegram) are skipped.
+    pub async fn next_batch(&mut self) -> Result<Vec<ReceivedUpdate>, Error> {
+        loop {
+            let update = self.updates_rx.recv().await
+                .ok_or(Error::ServerTerminated)?;
+            let mut updates = vec![update];
+            while let Ok(update) = self.updates_rx.try_recv() {
+                updates.push(update);
+            }
+            if let Some(last_update_id) = self.maybe_last_update_id {
+                updates.retain(|received| received.update.id > last_update_id);
+            }
+            if !updates.is_empty() {
+                return Ok(updates);
+            }
+        }
+    }
+
+    /// Stops taking updates and returns the ones queued but not taken yet.
+    /// Requests arriving from now on are answered with 503, Telegram delivers
+    /// them again to the next instance.
+    pub fn stop(&mut self) -> Vec<ReceivedUpdate> {
+        self.server_task.abort();
+        self.updates_rx.close();
+        let mut updates = Vec::new();
+        while let Ok(update) = self.updates_rx.try_recv() {
+            updates.push(update);
+        }
+        if let Some(last_update_id) = self.maybe_last_update_id {
+            updates.retain(|received| received.update.id > last_update_id);
+        }
+        log::info!("webhook is stopped, {} queued updates are left to dispatch", updates.len());
+        updates
+    }
+}
+
+impl Drop for Webhook {
+    fn drop(&mut self) {
+        self.server_task.abort();
+    }
+}
+
+struct Endpoint {
+    path: String,
+    secret_token: String,
+    updates_tx: mpsc::Sender<ReceivedUpdate>,
+}
+
+async fn run_server(listener: TcpListener, maybe_tls_acceptor: Option<TlsAcceptor>, endpoint: Arc<Endpoint>) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) =>
+                accepted,
+            Err(error) => {
+                log::error!("webhook accept failed: {:?}", error);
+                continue;
+            },
+        };
+        let maybe_tls_acceptor = maybe_tls_acceptor.clone();
+        let endpoint = endpoint.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(request, endpoint.clone()));
+            let result = match maybe_tls_acceptor {
+                Some(tls_acceptor) =>
+                    match tls_acceptor.accept(stream).await {
+                        Ok(tls_stream) =>
+                            Http::new().serve_connection(tls_stream, service).await,
+                        Err(error) => {
+                            log::warn!("webhook tls handshake with {} failed: {:?}", peer_addr, error);
+                            return;
+                        },
+                    },
+                None =>
+                    Http::new().serve_connection(stream, service).await,
+            };
+            if let Err(error) = result {
+                log::debug!("webhook connection with {} failed: {:?}", peer_addr, error);
+            }
+        });
+    }
+}
+
+async fn handle(request: Request<Body>, endpoint: Arc<Endpoint>) -> Result<Response<Body>, Infallible> {
+    let status = match check_request(&request, &endpoint) {
+        Ok(()) =>
+            receive_update(request, &endpoint).await,
+        Err(status) =>
+            status,
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+fn check_request(request: &Request<Body>, endpoint: &Endpoint) -> Result<(), StatusCode> {
+    if request.uri().path() != endpoint.path {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if request.method() != Method::POST {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let secret_token = request.headers().get(SECRET_TOKEN_HEADER)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    if !constant_time_eq(secret_token, endpoint.secret_token.as_bytes()) {
+        log::warn!("webhook request with wrong secret token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+async fn receive_update(request: Request<Body>, endpoint: &Endpoint) -> StatusCode {
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) =>
+            body,
+        Err(error) => {
+            log::warn!("failed to read webhook request body: {:?}", error);
+            return StatusCode::BAD_REQUEST;
+        },
+    };
+    let decoded = serde_json::from_slice::<serde_json::Value>(&body)
+        .and_then(|raw| Ok(ReceivedUpdate { update: serde_json::from_value(raw.clone())?, raw, }));
+    match decoded {
+        Ok(received) =>
+            match endpoint.updates_tx.try_send(received) {
+                Ok(()) =>
+                    StatusCode::OK,
+                Err(mpsc::error::TrySendError::Full(received)) => {
+                    log::warn!("webhook queue is full, update {} is left for redelivery", received.update.id);
+                    StatusCode::SERVICE_UNAVAILABLE
+                },
+                Err(mpsc::error::TrySendError::Closed(received)) => {
+                    log::debug!("webhook is stopped, update {} is left for redelivery", received.update.id);
+                    StatusCode::SERVICE_UNAVAILABLE
+                },
+            },
+        Err(error) => {
+            // same as in polling: an update which cannot be decoded is skipped, redelivery would not help
+            log::error!("failed to decode webhook update: {:?}", error);
+            StatusCode::OK
+        },
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn set_webhook(telegram_bot_token: &str, telegram_api_url: &str, webhook_url: &str, secret_token: &str) -> Result<(), Error> {
+    let client: Client<HttpsConnector<HttpConnector>> = Client::builder()
+        .build(HttpsConnector::new());
+    let params = serde_json::json!({
+        "url": webhook_url,
+        "secret_token": secret_token,
+    });
+    let request = Request::post(polling::method_url(telegram_api_url, telegram_bot_token, "setWebhook"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(params.to_string()))
+        .map_err(Error::SetWebhookRequestBuild)?;
+    let response = client.request(request).await
+        .map_err(Error::SetWebhookTransport)?;
+    let body = hyper::body::to_bytes(response.into_body()).await
+        .map_err(Error::SetWebhookResponseBody)?;
+    let response: SetWebhookResponse = serde_json::from_slice(&body)
+        .map_err(Error::SetWebhookResponseDecode)?;
+    if response.ok {
+        Ok(())
+    } else {
+        Err(Error::SetWebhook { description: response.description.unwrap_or_default(), })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+        },
+    };
+
+    use hyper::{
+        Body,
+        Request,
+        StatusCode,
+    };
+
+    use tokio::{
+        sync::{
+            mpsc,
+        },
+    };
+
+    use super::{
+        check_request,
+        constant_time_eq,
+        Endpoint,
+    };
+
+    fn request(path: &str, maybe_secret_token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::post(path);
+        if let Some(secret_token) = maybe_secret_token {
+            builder = builder.header("X-Telegram-Bot-Api-Secret-Token", secret_token);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn secret_token_is_checked() {
+        let (updates_tx, _updates_rx) = mpsc::channel(1);
+        let endpoint = Arc::new(Endpoint {
+            path: "/hook".to_string(),
+            secret_token: "s3cret".to_string(),
+            updates_tx,
+        });
+        assert_eq!(check_request(&request("/hook", Some("s3cret")), &endpoint), Ok(()));
+        assert_eq!(check_request(&request("/hook", Some("s3cre")), &endpoint), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(check_request(&request("/hook", None), &endpoint), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(check_request(&request("/other", Some("s3cret")), &endpoint), Err(StatusCode::NOT_FOUND));
+        let get = Request::get("/hook").header("X-Telegram-Bot-Api-Secret-Token", "s3cret").body(Body::empty()).unwrap();
+        assert_eq!(check_request(&get, &endpoint), Err(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn constant_time_eq_lengths() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+}
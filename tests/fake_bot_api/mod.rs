@@ -0,0 +1,282 @@
+//! Fake Telegram Bot API server for end-to-end tests: speaks `getUpdates`,
+//! `sendMessage`, `forwardMessage` and `deleteMessage`, delivers scripted
+//! updates and remembers which messages were deleted by their authors.
+
+use std::{
+    convert::Infallible,
+    net::{
+        SocketAddr,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+    collections::{
+        HashSet,
+    },
+};
+
+use hyper::{
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    Body,
+    Request,
+    Response,
+    Server,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use tokio::{
+    sync::{
+        Notify,
+        oneshot,
+    },
+};
+
+/// Bot API request received by the server.
+#[derive(Clone, Debug)]
+pub struct ApiCall {
+    pub method: String,
+    pub params: Value,
+}
+
+#[derive(Default)]
+struct State {
+    updates: Vec<Value>,
+    last_update_id: i64,
+    last_message_id: i64,
+    messages: HashSet<(i64, i64)>,
+    calls: Vec<ApiCall>,
+}
+
+pub struct FakeBotApi {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    updates_notify: Arc<Notify>,
+    maybe_shutdown_tx: Option<oneshot::Sender<()>>,
+}
+
+impl FakeBotApi {
+    pub async fn start() -> FakeBotApi {
+        let state = Arc::new(Mutex::new(State::default()));
+        let updates_notify = Arc::new(Notify::new());
+        let make_service = {
+            let state = state.clone();
+            let updates_notify = updates_notify.clone();
+            make_service_fn(move |_connection| {
+                let state = state.clone();
+                let updates_notify = updates_notify.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        handle(request, state.clone(), updates_notify.clone())
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async { shutdown_rx.await.ok(); }));
+        FakeBotApi {
+            addr,
+            state,
+            updates_notify,
+            maybe_shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    /// Value for the bot `--telegram-api-url` option.
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Queues a text message update from `user_id` in the group `chat_id`,
+    /// returns the message id.
+    pub fn send_group_message(&self, chat_id: i64, user_id: i64, username: &str, text: &str) -> i64 {
+        let update = self.group_message_update(chat_id, user_id, username, text);
+        let message_id = update["message"]["message_id"].as_i64().unwrap();
+        self.state.lock().unwrap().updates.push(update);
+        self.updates_notify.notify_waiters();
+        message_id
+    }
+
+    /// Text message update from `user_id` in the group `chat_id` without
+    /// queueing it for `getUpdates`, e.g. to push it to the bot webhook.
+    pub fn group_message_update(&self, chat_id: i64, user_id: i64, username: &str, text: &str) -> Value {
+        let mut state = self.state.lock().unwrap();
+        state.last_message_id += 1;
+        let message_id = state.last_message_id;
+        state.messages.insert((chat_id, message_id));
+        state.last_update_id += 1;
+        json!({
+            "update_id": state.last_update_id,
+            "message": {
+                "message_id": message_id,
+                "from": user(user_id, username, false),
+                "chat": group(chat_id),
+                "date": 0,
+                "text": text,
+            },
+        })
+    }
+
+    /// The author deletes the message: forwarding it fails from now on.
+    pub fn delete_message(&self, chat_id: i64, message_id: i64) {
+        self.state.lock().unwrap().messages.remove(&(chat_id, message_id));
+    }
+
+    pub fn calls(&self, method: &str) -> Vec<ApiCall> {
+        self.state.lock().unwrap().calls.iter()
+            .filter(|call| call.method == method)
+            .cloned()
+            .collect()
+    }
+
+    /// Waits until the bot confirms all the queued updates.
+    pub async fn wait_for_updates_confirmed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.state.lock().unwrap().updates.is_empty() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    /// Waits until the bot makes a `method` call matching `predicate`.
+    pub async fn wait_for_call<P>(&self, method: &str, timeout: Duration, predicate: P) -> Option<ApiCall> where P: Fn(&Value) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(call) = self.calls(method).into_iter().find(|call| predicate(&call.params)) {
+                return Some(call);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+}
+
+impl Drop for FakeBotApi {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.maybe_shutdown_tx.take() {
+            shutdown_tx.send(()).ok();
+        }
+    }
+}
+
+fn user(user_id: i64, username: &str, is_bot: bool) -> Value {
+    json!({
+        "id": user_id,
+        "is_bot": is_bot,
+        "first_name": username,
+        "username": username,
+    })
+}
+
+fn group(chat_id: i64) -> Value {
+    json!({
+        "id": chat_id,
+        "type": "group",
+        "title": format!("group {}", chat_id),
+        "all_members_are_administrators": false,
+    })
+}
+
+fn ok(result: Value) -> Value {
+    json!({ "ok": true, "result": result, })
+}
+
+fn bad_request(description: &str) -> Value {
+    json!({ "ok": false, "error_code": 400, "description": format!("Bad Request: {}", description), })
+}
+
+async fn handle(request: Request<Body>, state: Arc<Mutex<State>>, updates_notify: Arc<Notify>) -> Result<Response<Body>, Infallible> {
+    let method = request.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+    let body = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default();
+    let params: Value = serde_json::from_slice(&body).unwrap_or_else(|_| json!({}));
+    state.lock().unwrap().calls.push(ApiCall { method: method.clone(), params: params.clone(), });
+
+    let reply = match method.as_str() {
+        "getUpdates" =>
+            get_updates(&params, &state, &updates_notify).await,
+        "sendMessage" => {
+            let mut state = state.lock().unwrap();
+            let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+            state.last_message_id += 1;
+            let message_id = state.last_message_id;
+            state.messages.insert((chat_id, message_id));
+            ok(json!({
+                "message_id": message_id,
+                "from": user(1, "beercan_bot", true),
+                "chat": group(chat_id),
+                "date": 0,
+                "text": params["text"],
+            }))
+        },
+        "forwardMessage" => {
+            let mut state = state.lock().unwrap();
+            let from_chat_id = params["from_chat_id"].as_i64().unwrap_or_default();
+            let message_id = params["message_id"].as_i64().unwrap_or_default();
+            if state.messages.contains(&(from_chat_id, message_id)) {
+                let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+                state.last_message_id += 1;
+                let forwarded_message_id = state.last_message_id;
+                state.messages.insert((chat_id, forwarded_message_id));
+                ok(json!({
+                    "message_id": forwarded_message_id,
+                    "from": user(1, "beercan_bot", true),
+                    "chat": group(chat_id),
+                    "date": 0,
+                    "forward_date": 0,
+                    "text": "",
+                }))
+            } else {
+                bad_request("message to forward not found")
+            }
+        },
+        "deleteMessage" => {
+            let chat_id = params["chat_id"].as_i64().unwrap_or_default();
+            let message_id = params["message_id"].as_i64().unwrap_or_default();
+            if state.lock().unwrap().messages.remove(&(chat_id, message_id)) {
+                ok(json!(true))
+            } else {
+                bad_request("message to delete not found")
+            }
+        },
+        _ =>
+            json!({ "ok": false, "error_code": 404, "description": "Not Found", }),
+    };
+    Ok(Response::new(Body::from(reply.to_string())))
+}
+
+/// Long polling: answers as soon as there are updates at or above `offset`,
+/// or when the requested timeout (capped at a second) expires.
+async fn get_updates(params: &Value, state: &Mutex<State>, updates_notify: &Notify) -> Value {
+    let offset = params["offset"].as_i64().unwrap_or_default();
+    let timeout = Duration::from_secs(params["timeout"].as_u64().unwrap_or_default().min(1));
+    let deadline = Instant::now() + timeout;
+    loop {
+        let notified = updates_notify.notified();
+        {
+            let mut state = state.lock().unwrap();
+            // updates below the offset are confirmed
+            state.updates.retain(|update| update["update_id"].as_i64().unwrap_or_default() >= offset);
+            if !state.updates.is_empty() || Instant::now() >= deadline {
+                return ok(Value::Array(state.updates.clone()));
+            }
+        }
+        tokio::time::timeout(deadline.saturating_duration_since(Instant::now()), notified).await.ok();
+    }
+}
@@ -0,0 +1,237 @@
+//! End-to-end scenarios: the bot binary runs against the fake Bot API server.
+
+use std::{
+    fs,
+    net::{
+        SocketAddr,
+        TcpListener,
+    },
+    path::{
+        PathBuf,
+    },
+    process::{
+        Child,
+        Command,
+        ExitStatus,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use hyper::{
+    Body,
+    Client,
+    Request,
+    StatusCode,
+};
+
+use serde_json::{
+    Value,
+};
+
+mod fake_bot_api;
+
+use fake_bot_api::{
+    FakeBotApi,
+};
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+const WEBHOOK_SECRET_TOKEN: &str = "s3cret";
+
+/// Running bot binary, killed on drop.
+struct Bot {
+    child: Child,
+    work_dir: PathBuf,
+}
+
+impl Bot {
+    fn start(name: &str, fake_bot_api: &FakeBotApi, config: &str) -> Bot {
+        Bot::start_with_args(name, fake_bot_api, config, &[])
+    }
+
+    fn start_with_args(name: &str, fake_bot_api: &FakeBotApi, config: &str, args: &[&str]) -> Bot {
+        let work_dir = std::env::temp_dir().join(format!("beercan-e2e-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&work_dir).unwrap();
+        let config_path = work_dir.join("beercan.toml");
+        fs::write(&config_path, config).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_beercan-bot"))
+            .arg("--telegram-bot-token").arg("test-token")
+            .arg("--config").arg(&config_path)
+            .arg("--state-file").arg(work_dir.join("state.json"))
+            .arg("--telegram-api-url").arg(fake_bot_api.url())
+            .arg("--poll-timeout-s").arg("1")
+            .args(args)
+            .spawn()
+            .unwrap();
+        Bot { child, work_dir, }
+    }
+
+    /// Sends SIGTERM and waits for the bot to exit.
+    async fn terminate(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        self.signal("-TERM");
+        self.wait_exit(timeout).await
+    }
+
+    fn signal(&self, signal: &str) {
+        let status = Command::new("kill")
+            .arg(signal)
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    async fn wait_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return Some(status);
+            }
+            // the fake server runs on this thread: keep it serving the shutdown requests
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        fs::remove_dir_all(&self.work_dir).ok();
+    }
+}
+
+#[tokio::test]
+async fn vaccine_reminder_replies_to_question() {
+    let fake_bot_api = FakeBotApi::start().await;
+    let _bot = Bot::start("vaccine_reminder", &fake_bot_api, r#"
+        [[vaccine_reminder]]
+        user_id = 100
+        group_id = -10
+    "#);
+
+    fake_bot_api.send_group_message(-10, 200, "someone", "а где тут вопрос?");
+    let message_id = fake_bot_api.send_group_message(-10, 100, "patient", "кто пойдёт гулять?");
+    fake_bot_api.send_group_message(-10, 100, "patient", "я пошёл");
+
+    let reply = fake_bot_api.wait_for_call("sendMessage", CALL_TIMEOUT, |params| params["chat_id"] == -10).await
+        .expect("no reply");
+    assert_eq!(reply.params["reply_to_message_id"], message_id);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(fake_bot_api.calls("sendMessage").len(), 1);
+}
+
+#[tokio::test]
+async fn delete_recover_reports_deleted_message() {
+    let fake_bot_api = FakeBotApi::start().await;
+    let _bot = Bot::start("delete_recover", &fake_bot_api, r#"
+        [[delete_recover]]
+        user_id = 100
+        group_id = -10
+        forward_group_id = -20
+        check_timeout_s = 1
+    "#);
+
+    let kept_message_id = fake_bot_api.send_group_message(-10, 100, "villain", "это останется");
+    let deleted_message_id = fake_bot_api.send_group_message(-10, 100, "villain", "это я удалю");
+    assert!(fake_bot_api.wait_for_updates_confirmed(CALL_TIMEOUT).await, "updates are not received");
+    fake_bot_api.delete_message(-10, deleted_message_id);
+
+    let report = fake_bot_api.wait_for_call("sendMessage", CALL_TIMEOUT, |params| params["chat_id"] == -10).await
+        .expect("deletion is not reported");
+    let text = report.params["text"].as_str().unwrap();
+    assert!(text.contains("@villain"));
+    assert!(text.contains("это я удалю"));
+
+    // the kept message is probed again and again, its copies are cleaned up
+    let forwards = fake_bot_api.calls("forwardMessage");
+    assert!(forwards.iter().any(|call| call.params["message_id"] == kept_message_id && call.params["chat_id"] == -20));
+    assert!(fake_bot_api.calls("deleteMessage").iter().all(|call| call.params["chat_id"] == -20));
+}
+
+#[tokio::test]
+async fn second_signal_cuts_shutdown_short() {
+    let fake_bot_api = FakeBotApi::start().await;
+    let mut bot = Bot::start("second_signal", &fake_bot_api, r#"
+        [[delete_recover]]
+        user_id = 100
+        group_id = -10
+        forward_group_id = -20
+    "#);
+
+    // the final check on stop forwards the whole window 3 s apart
+    for index in 0 .. 8 {
+        fake_bot_api.send_group_message(-10, 100, "villain", &format!("message {}", index));
+    }
+    assert!(fake_bot_api.wait_for_updates_confirmed(CALL_TIMEOUT).await, "updates are not received");
+    bot.signal("-TERM");
+    fake_bot_api.wait_for_call("forwardMessage", CALL_TIMEOUT, |params| params["chat_id"] == -20).await
+        .expect("final check is not started");
+    bot.signal("-INT");
+
+    let status = bot.wait_exit(Duration::from_secs(5)).await
+        .expect("bot waits for the final check after the second signal");
+    assert!(status.success());
+    assert!(fake_bot_api.calls("forwardMessage").len() < 8);
+}
+
+fn free_local_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Pushes the update to the bot webhook the way Telegram does, retrying
+/// until the bot is listening. Returns the response status.
+async fn push_update(webhook_addr: SocketAddr, update: &Value) -> StatusCode {
+    let client = Client::new();
+    let deadline = Instant::now() + CALL_TIMEOUT;
+    loop {
+        let request = Request::post(format!("http://{}/", webhook_addr))
+            .header("content-type", "application/json")
+            .header("x-telegram-bot-api-secret-token", WEBHOOK_SECRET_TOKEN)
+            .body(Body::from(update.to_string()))
+            .unwrap();
+        match client.request(request).await {
+            Ok(response) =>
+                return response.status(),
+            Err(_not_listening_yet) if Instant::now() < deadline =>
+                tokio::time::sleep(Duration::from_millis(100)).await,
+            Err(error) =>
+                panic!("webhook is not listening: {:?}", error),
+        }
+    }
+}
+
+#[tokio::test]
+async fn webhook_updates_accepted_before_shutdown_are_handled() {
+    let fake_bot_api = FakeBotApi::start().await;
+    let webhook_addr = free_local_addr();
+    let mut bot = Bot::start_with_args("webhook", &fake_bot_api, r#"
+        [[vaccine_reminder]]
+        user_id = 100
+        group_id = -10
+    "#, &[
+        "--mode", "webhook",
+        "--webhook-listen-addr", &webhook_addr.to_string(),
+        "--webhook-secret-token", WEBHOOK_SECRET_TOKEN,
+    ]);
+
+    let question = fake_bot_api.group_message_update(-10, 100, "patient", "кто пойдёт гулять?");
+    assert_eq!(push_update(webhook_addr, &question).await, StatusCode::OK);
+    let reply = fake_bot_api.wait_for_call("sendMessage", CALL_TIMEOUT, |params| params["chat_id"] == -10).await
+        .expect("no reply");
+    assert_eq!(reply.params["reply_to_message_id"], question["message"]["message_id"]);
+
+    // answered with 200 right before SIGTERM: must be handled on the way out
+    let last_question = fake_bot_api.group_message_update(-10, 100, "patient", "а кто не пойдёт?");
+    assert_eq!(push_update(webhook_addr, &last_question).await, StatusCode::OK);
+    let status = bot.terminate(CALL_TIMEOUT).await
+        .expect("bot did not exit");
+    assert!(status.success());
+
+    let replies = fake_bot_api.calls("sendMessage");
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[1].params["reply_to_message_id"], last_question["message"]["message_id"]);
+}